@@ -1,4 +1,5 @@
 use crate::value::{print_value, Value, ValueArray};
+use std::ops::Range;
 
 #[repr(u8)]
 pub enum OpCode {
@@ -13,11 +14,38 @@ pub enum OpCode {
     Nil = 8,
     True = 9,
     False = 10,
+    Print = 11,
+    Pop = 12,
+    DefineGlobal = 13,
+    GetGlobal = 14,
+    SetGlobal = 15,
+    GetLocal = 16,
+    SetLocal = 17,
+    Jump = 18,
+    JumpIfFalse = 19,
+    Loop = 20,
+    Not = 21,
+    Equal = 22,
+    Greater = 23,
+    Less = 24,
+    Call = 25,
+    Closure = 26,
+    GetUpvalue = 27,
+    SetUpvalue = 28,
+    CloseUpvalue = 29,
+    Class = 30,
+    GetProperty = 31,
+    SetProperty = 32,
+    Method = 33,
+    Inherit = 34,
+    GetSuper = 35,
+    SuperInvoke = 36,
+    Invoke = 37,
 }
 
-impl Into<u8> for OpCode {
-    fn into(self) -> u8 {
-        self as u8
+impl From<OpCode> for u8 {
+    fn from(op: OpCode) -> u8 {
+        op as u8
     }
 }
 
@@ -34,10 +62,40 @@ impl OpCode {
             8 => OpCode::Nil,
             9 => OpCode::True,
             10 => OpCode::False,
+            11 => OpCode::Print,
+            12 => OpCode::Pop,
+            13 => OpCode::DefineGlobal,
+            14 => OpCode::GetGlobal,
+            15 => OpCode::SetGlobal,
+            16 => OpCode::GetLocal,
+            17 => OpCode::SetLocal,
+            18 => OpCode::Jump,
+            19 => OpCode::JumpIfFalse,
+            20 => OpCode::Loop,
+            21 => OpCode::Not,
+            22 => OpCode::Equal,
+            23 => OpCode::Greater,
+            24 => OpCode::Less,
+            25 => OpCode::Call,
+            26 => OpCode::Closure,
+            27 => OpCode::GetUpvalue,
+            28 => OpCode::SetUpvalue,
+            29 => OpCode::CloseUpvalue,
+            30 => OpCode::Class,
+            31 => OpCode::GetProperty,
+            32 => OpCode::SetProperty,
+            33 => OpCode::Method,
+            34 => OpCode::Inherit,
+            35 => OpCode::GetSuper,
+            36 => OpCode::SuperInvoke,
+            37 => OpCode::Invoke,
             _ => OpCode::Unknown,
         }
     }
 
+    /// The length of the instruction, including its operands.
+    /// A closure is followed by two more bytes per captured variable,
+    /// which `Chunk::instruction_length` accounts for.
     pub fn code_length(&self) -> usize {
         match *self {
             OpCode::Return => 1,
@@ -51,51 +109,137 @@ impl OpCode {
             OpCode::Nil => 1,
             OpCode::True => 1,
             OpCode::False => 1,
+            OpCode::Print => 1,
+            OpCode::Pop => 1,
+            OpCode::DefineGlobal => 2,
+            OpCode::GetGlobal => 2,
+            OpCode::SetGlobal => 2,
+            OpCode::GetLocal => 2,
+            OpCode::SetLocal => 2,
+            OpCode::Jump => 3,
+            OpCode::JumpIfFalse => 3,
+            OpCode::Loop => 3,
+            OpCode::Not => 1,
+            OpCode::Equal => 1,
+            OpCode::Greater => 1,
+            OpCode::Less => 1,
+            OpCode::Call => 2,
+            OpCode::Closure => 2,
+            OpCode::GetUpvalue => 2,
+            OpCode::SetUpvalue => 2,
+            OpCode::CloseUpvalue => 1,
+            OpCode::Class => 2,
+            OpCode::GetProperty => 2,
+            OpCode::SetProperty => 2,
+            OpCode::Method => 2,
+            OpCode::Inherit => 1,
+            OpCode::GetSuper => 2,
+            OpCode::SuperInvoke => 3,
+            OpCode::Invoke => 3,
         }
     }
 }
 
 type Offset = usize;
 
+/// Where a run of bytes in a chunk was compiled from
+#[derive(Clone, Debug, PartialEq)]
+pub struct Position {
+    /// The offset in `code` of the run's first byte
+    start: Offset,
+    pub line: u32,
+    pub column: u32,
+    /// The source text, as byte offsets
+    pub span: Range<usize>,
+}
+
 #[derive(Clone)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<u32>,
+    /// Ordered by `start`. Consecutive bytes from the same token, like an
+    /// instruction and its operands, share one entry.
+    pub(crate) positions: Vec<Position>,
     pub constants: ValueArray,
 }
 
+impl Default for Chunk {
+    fn default() -> Chunk {
+        Chunk::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
             // TODO: use with_capacity(8) here?
             code: Vec::new(),
             constants: ValueArray::new(),
-            lines: Vec::new(),
+            positions: Vec::new(),
         }
     }
 
-    pub fn code_iter(&self) -> ChunkCodeIterator {
+    pub fn code_iter(&self) -> ChunkCodeIterator<'_> {
         ChunkCodeIterator {
             offset: 0,
-            chunk: &self,
+            chunk: self,
         }
     }
 
-    pub fn write<T: Into<u8>>(&mut self, byte: T, line: u32) {
+    pub fn write<T: Into<u8>>(&mut self, byte: T, line: u32, column: u32, span: Range<usize>) {
+        let start = self.code.len();
         self.code.push(byte.into());
-        self.lines.push(line);
+        if let Some(last) = self.positions.last() {
+            if (last.line, last.column, &last.span) == (line, column, &span) {
+                return;
+            }
+        }
+        self.positions.push(Position {
+            start,
+            line,
+            column,
+            span,
+        });
+    }
+
+    /// Where the byte at `offset` was compiled from
+    pub fn position(&self, offset: Offset) -> &Position {
+        let run = self.positions.partition_point(|p| p.start <= offset);
+        &self.positions[run - 1]
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
+        // objects compare by identity, so reusing an existing
+        // constant is only worthwhile for interned strings
+        if value.as_obj().is_some() {
+            if let Some(idx) = self.constants.iter().position(|c| *c == value) {
+                return idx;
+            }
+        }
+
         let new_index = self.constants.len();
         self.constants.push(value);
         new_index
     }
 
+    /// The length of the instruction starting at `offset`
+    pub fn instruction_length(&self, offset: usize) -> usize {
+        let op_code = OpCode::from_int(self.code[offset]);
+        match op_code {
+            OpCode::Closure => {
+                let upvalue_count = self.constants[self.code[offset + 1] as usize]
+                    .as_obj()
+                    .and_then(|o| o.as_function().map(|f| f.upvalue_count))
+                    .unwrap_or(0);
+                op_code.code_length() + 2 * upvalue_count
+            }
+            _ => op_code.code_length(),
+        }
+    }
+
     pub fn disassemble_with_iterator(&self, name: &str) {
         println!("== {} == ", name);
         for (offset, instr) in self.code_iter() {
-            self.disassemble_instruction(offset, &instr);
+            self.disassemble_instruction(offset, instr);
         }
     }
 
@@ -106,13 +250,46 @@ impl Chunk {
         println!();
     }
 
+    /// A method call: the method's name constant, then the argument count
+    fn disassemble_invoke(&self, name: &str, instruction: &[u8]) {
+        let constant_idx = instruction[1] as usize;
+        print!("{} ({} args) {:04} '", name, instruction[2], constant_idx);
+        print_value(self.constants[constant_idx]);
+        println!();
+    }
+
+    fn disassemble_byte(&self, name: &str, instruction: &[u8]) {
+        println!("{} {:04}", name, instruction[1]);
+    }
+
+    fn disassemble_jump(&self, name: &str, forward: bool, offset: usize, instruction: &[u8]) {
+        let jump = (instruction[1] as usize) << 8 | instruction[2] as usize;
+        let next = offset + instruction.len();
+        let target = if forward { next + jump } else { next - jump };
+        println!("{} {:04} -> {:04}", name, offset, target);
+    }
+
+    fn disassemble_closure(&self, offset: usize, instruction: &[u8]) {
+        self.disassemble_constant("OP_CLOSURE", instruction);
+        for (i, pair) in instruction[2..].chunks(2).enumerate() {
+            let kind = if pair[0] == 1 { "local" } else { "upvalue" };
+            println!(
+                "{:04}    |                     {} {}",
+                offset + 2 + i * 2,
+                kind,
+                pair[1]
+            );
+        }
+    }
+
     fn disassemble_instruction(&self, offset: usize, instruction: &[u8]) {
         print!("{:04} ", offset);
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
+        let line = self.position(offset).line;
+        if offset > 0 && line == self.position(offset - 1).line {
             print!("   | ");
         } else {
-            print!("{:04} ", self.lines[offset]);
+            print!("{:04} ", line);
         }
 
         let op_byte = instruction[0];
@@ -129,6 +306,35 @@ impl Chunk {
             OpCode::Nil => println!("OP_NIL"),
             OpCode::True => println!("OP_TRUE"),
             OpCode::False => println!("OP_FALSE"),
+            OpCode::Print => println!("OP_PRINT"),
+            OpCode::Pop => println!("OP_POP"),
+            OpCode::DefineGlobal => self.disassemble_constant("OP_DEFINE_GLOBAL", instruction),
+            OpCode::GetGlobal => self.disassemble_constant("OP_GET_GLOBAL", instruction),
+            OpCode::SetGlobal => self.disassemble_constant("OP_SET_GLOBAL", instruction),
+            OpCode::GetLocal => self.disassemble_byte("OP_GET_LOCAL", instruction),
+            OpCode::SetLocal => self.disassemble_byte("OP_SET_LOCAL", instruction),
+            OpCode::Jump => self.disassemble_jump("OP_JUMP", true, offset, instruction),
+            OpCode::JumpIfFalse => {
+                self.disassemble_jump("OP_JUMP_IF_FALSE", true, offset, instruction)
+            }
+            OpCode::Loop => self.disassemble_jump("OP_LOOP", false, offset, instruction),
+            OpCode::Not => println!("OP_NOT"),
+            OpCode::Equal => println!("OP_EQUAL"),
+            OpCode::Greater => println!("OP_GREATER"),
+            OpCode::Less => println!("OP_LESS"),
+            OpCode::Call => self.disassemble_byte("OP_CALL", instruction),
+            OpCode::Closure => self.disassemble_closure(offset, instruction),
+            OpCode::GetUpvalue => self.disassemble_byte("OP_GET_UPVALUE", instruction),
+            OpCode::SetUpvalue => self.disassemble_byte("OP_SET_UPVALUE", instruction),
+            OpCode::CloseUpvalue => println!("OP_CLOSE_UPVALUE"),
+            OpCode::Class => self.disassemble_constant("OP_CLASS", instruction),
+            OpCode::GetProperty => self.disassemble_constant("OP_GET_PROPERTY", instruction),
+            OpCode::SetProperty => self.disassemble_constant("OP_SET_PROPERTY", instruction),
+            OpCode::Method => self.disassemble_constant("OP_METHOD", instruction),
+            OpCode::Inherit => println!("OP_INHERIT"),
+            OpCode::GetSuper => self.disassemble_constant("OP_GET_SUPER", instruction),
+            OpCode::SuperInvoke => self.disassemble_invoke("OP_SUPER_INVOKE", instruction),
+            OpCode::Invoke => self.disassemble_invoke("OP_INVOKE", instruction),
             OpCode::Unknown => println!("Unknown opcode {:?}", instruction),
         }
     }
@@ -148,12 +354,23 @@ impl<'a> Iterator for ChunkCodeIterator<'a> {
         }
         let current_offset = self.offset;
 
-        let current_code = self.chunk.code[self.offset];
-        let op_code = OpCode::from_int(current_code);
-        let code_length = op_code.code_length();
+        let code_length = self.chunk.instruction_length(self.offset);
 
         let arr = &self.chunk.code[self.offset..self.offset + code_length];
         self.offset += code_length;
         Some((current_offset, arr))
     }
 }
+
+#[test]
+fn chunk_shares_positions_between_bytes_of_a_token() {
+    let mut chunk = Chunk::new();
+    chunk.write(OpCode::Constant, 1, 1, 0..3);
+    chunk.write(0, 1, 1, 0..3);
+    chunk.write(OpCode::Print, 2, 5, 9..14);
+    chunk.write(OpCode::Return, 2, 5, 9..14);
+    assert_eq!(chunk.positions.len(), 2);
+    assert_eq!(chunk.position(1).span, 0..3);
+    let last = chunk.position(3);
+    assert_eq!((last.line, last.column, last.span.clone()), (2, 5, 9..14));
+}
@@ -1,71 +1,205 @@
 use crate::chunk::{Chunk, OpCode};
+use crate::diagnostic::Diagnostic;
+use crate::memory::{Heap, Trace};
+use crate::object::{Obj, ObjFunction, ObjRef};
 use crate::scanner::Scanner;
 use crate::token::{Token, TokenType};
 use crate::value::Value;
 use crate::vm::InterpretError;
 use std::str::FromStr;
 
+const LOCALS_MAX: usize = 256;
+const UPVALUES_MAX: usize = 256;
+
 pub struct Compiler<'c> {
     scanner: Scanner<'c>,
     current: Token<'c>,
     previous: Token<'c>,
-    had_error: bool,
+    /// Every error reported so far
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
-    chunk: Chunk,
     options: Options,
+    /// One entry per function being compiled, innermost last
+    compilers: Vec<FunctionCompiler<'c>>,
+    /// One entry per class body being compiled, innermost last
+    classes: Vec<ClassCompiler>,
+    heap: &'c mut Heap,
+    /// Objects the caller needs kept alive if compiling triggers a collection
+    roots: &'c dyn Trace,
+}
+
+/// What a collection made while compiling must keep alive
+struct CompilerRoots<'r> {
+    caller: &'r dyn Trace,
+    compilers: &'r [FunctionCompiler<'r>],
+}
+
+impl Trace for CompilerRoots<'_> {
+    fn trace(&self, heap: &mut Heap) {
+        self.caller.trace(heap);
+        // constants of the functions still being compiled live only in the compiler
+        for compiler in self.compilers {
+            compiler.function.trace(heap);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// The state of a single function body being compiled
+struct FunctionCompiler<'c> {
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local<'c>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
 }
 
+impl<'c> FunctionCompiler<'c> {
+    fn new(function_type: FunctionType, name: Option<ObjRef>) -> FunctionCompiler<'c> {
+        let mut locals = Vec::with_capacity(LOCALS_MAX);
+        // slot zero holds the function being called, or the receiver in methods
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => Token::synthetic("this"),
+            FunctionType::Function | FunctionType::Script => Token::empty(),
+        };
+        locals.push(Local {
+            name: slot_zero,
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        FunctionCompiler {
+            function: ObjFunction::new(name),
+            function_type,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+/// The state of a single class body being compiled
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+/// A local variable that lives in a stack slot while its scope is active
+struct Local<'c> {
+    name: Token<'c>,
+    /// `None` while the variable's initializer is being compiled
+    depth: Option<usize>,
+    /// Whether a closure refers to this local, so it must outlive its stack slot
+    is_captured: bool,
+}
+
+/// A variable captured by a closure from an enclosing function
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Upvalue {
+    /// A local slot in the enclosing function if `is_local`,
+    /// otherwise an index into the enclosing function's upvalues
+    index: u8,
+    is_local: bool,
+}
+
+/// Debugging output, all of it off by default
+#[derive(Clone, Copy, Default)]
 pub struct Options {
+    /// Disassemble each function once it's compiled
     debug_print_code: bool,
+    /// Print the VM's stack before every instruction
+    pub(crate) trace_execution: bool,
 }
 
 impl Options {
     pub fn debug() -> Self {
         Options {
             debug_print_code: true,
+            trace_execution: true,
         }
     }
 }
 
-impl Default for Options {
-    fn default() -> Self {
-        Options {
-            debug_print_code: false,
-        }
-    }
-}
-
-pub fn compile(source: &str, options: Options) -> Result<Chunk, InterpretError> {
-    Compiler::new(source, options).compile()
+/// Compiles `source` into a function holding the top level script.
+/// Anything reachable from `roots` survives collections made while compiling.
+pub fn compile(
+    source: &str,
+    heap: &mut Heap,
+    roots: &dyn Trace,
+    options: Options,
+) -> Result<ObjRef, InterpretError> {
+    Compiler::new(source, heap, roots, options).compile()
 }
 
-impl Compiler<'c> {
-    pub fn new(source: &'c str, options: Options) -> Compiler<'c> {
+impl<'c> Compiler<'c> {
+    pub fn new(
+        source: &'c str,
+        heap: &'c mut Heap,
+        roots: &'c dyn Trace,
+        options: Options,
+    ) -> Compiler<'c> {
         Compiler {
             scanner: Scanner::new(source),
-            chunk: Chunk::new(),
-            had_error: false,
+            diagnostics: Vec::new(),
             panic_mode: false,
             current: Token::empty(),
             previous: Token::empty(),
             options,
+            compilers: vec![FunctionCompiler::new(FunctionType::Script, None)],
+            classes: Vec::new(),
+            heap,
+            roots,
         }
     }
 
-    pub fn compile(&mut self) -> Result<Chunk, InterpretError> {
+    pub fn compile(&mut self) -> Result<ObjRef, InterpretError> {
         self.advance();
-        self.expression();
-        self.consume(&TokenType::Eof, "Expected end of file.");
-        self.end_compilation();
-        if self.had_error {
-            Err(InterpretError::RuntimeError)
+        while !self.consume_match(&TokenType::Eof) {
+            self.declaration();
+        }
+        let (function, _) = self.end_compilation();
+        if self.diagnostics.is_empty() {
+            Ok(function)
         } else {
-            Ok(self.chunk.clone())
+            let diagnostics = std::mem::take(&mut self.diagnostics);
+            Err(InterpretError::CompileError(diagnostics))
         }
     }
 
+    fn current_compiler(&mut self) -> &mut FunctionCompiler<'c> {
+        self.compilers
+            .last_mut()
+            .expect("there is always a function being compiled")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current_compiler().function.chunk
+    }
+
+    fn scope_depth(&self) -> usize {
+        self.compilers
+            .last()
+            .expect("there is always a function being compiled")
+            .scope_depth
+    }
+
     pub fn emit_byte<T: Into<u8>>(&mut self, byte: T) {
-        self.chunk.write(byte, self.previous.line as u32);
+        let previous = self.previous.clone();
+        self.emit_byte_at(byte, &previous);
+    }
+
+    /// Emits a byte attributed to `token` rather than the last one consumed,
+    /// for instructions whose operands are compiled first
+    fn emit_byte_at<T: Into<u8>>(&mut self, byte: T, token: &Token) {
+        let (line, column) = (token.line as u32, token.column as u32);
+        let span = token.span();
+        self.current_chunk().write(byte, line, column, span);
     }
 
     pub fn emit_bytes<T: Into<u8>, U: Into<u8>>(&mut self, byte_1: T, byte_2: U) {
@@ -73,15 +207,90 @@ impl Compiler<'c> {
         self.emit_byte(byte_2);
     }
 
-    pub fn end_compilation(&mut self) {
+    /// Emits a jump instruction with a placeholder operand
+    /// and returns the operand's offset so it can be patched later
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction);
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        let code = &mut self.current_chunk().code;
+        code[offset] = ((jump >> 8) & 0xff) as u8;
+        code[offset + 1] = (jump & 0xff) as u8;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop);
+
+        // +2 to skip over the loop's own operand
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        self.emit_bytes(((offset >> 8) & 0xff) as u8, (offset & 0xff) as u8);
+    }
+
+    /// Finishes the innermost function and moves it onto the heap,
+    /// returning it along with the variables it captures
+    fn end_compilation(&mut self) -> (ObjRef, Vec<Upvalue>) {
         self.emit_return();
-        if self.options.debug_print_code && !self.had_error {
-            self.chunk.disassemble_with_iterator("Main chunk");
+        let compiler = self
+            .compilers
+            .pop()
+            .expect("there is always a function being compiled");
+        let mut function = compiler.function;
+        function.upvalue_count = compiler.upvalues.len();
+
+        if self.options.debug_print_code && self.diagnostics.is_empty() {
+            let name = match function.name {
+                Some(name) => name.to_string(),
+                None => "<script>".to_owned(),
+            };
+            function.chunk.disassemble_with_iterator(&name);
         }
+
+        (self.alloc(Obj::Function(function)), compiler.upvalues)
+    }
+
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let obj = self.heap.alloc(obj);
+        self.collect_garbage_if_needed(obj);
+        obj
+    }
+
+    fn copy_string(&mut self, chars: &str) -> ObjRef {
+        let s = self.heap.copy_string(chars);
+        self.collect_garbage_if_needed(s);
+        s
+    }
+
+    fn collect_garbage_if_needed(&mut self, new: ObjRef) {
+        let roots = CompilerRoots {
+            caller: self.roots,
+            compilers: &self.compilers,
+        };
+        self.heap.collect_if_needed(new, &roots);
     }
 
+    /// Functions without an explicit return value return nil,
+    /// except initializers which return the instance
     pub fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return)
+        if self.current_compiler().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal, 0);
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+        self.emit_byte(OpCode::Return);
     }
 
     pub fn emit_constant(&mut self, v: Value) {
@@ -90,8 +299,8 @@ impl Compiler<'c> {
     }
 
     pub fn make_constant(&mut self, v: Value) -> u8 {
-        let constant = self.chunk.add_constant(v);
-        if constant > std::u8::MAX as usize {
+        let constant = self.current_chunk().add_constant(v);
+        if constant > u8::MAX as usize {
             self.error("Too many constants in one chunk");
             0
         } else {
@@ -101,54 +310,686 @@ impl Compiler<'c> {
 
     pub fn number(&mut self) {
         match f64::from_str(self.previous.as_slice()) {
-            Ok(v) => self.emit_constant(Value::Number(v)),
+            Ok(v) => self.emit_constant(Value::from(v)),
             Err(_) => self.error("Invalid number"),
         }
     }
 
+    pub fn string(&mut self) {
+        // trim the surrounding quotes
+        let lexeme = self.previous.as_slice();
+        let chars = lexeme[1..lexeme.len() - 1].to_owned();
+        let s = self.heap.take_string(chars);
+        self.collect_garbage_if_needed(s);
+        self.emit_constant(Value::from(s));
+    }
+
     pub fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
 
+    pub fn declaration(&mut self) {
+        if self.consume_match(&TokenType::Class) {
+            self.class_declaration();
+        } else if self.consume_match(&TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.consume_match(&TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    /// Skips tokens until what is probably the start of the next statement,
+    /// so that one mistake doesn't cause a cascade of errors
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.t != TokenType::Eof {
+            if self.previous.t == TokenType::Semicolon {
+                return;
+            }
+            match self.current.t {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    pub fn class_declaration(&mut self) {
+        self.consume(&TokenType::Identifier, "Expect class name.");
+        let name = self.previous.clone();
+        let name_constant = self.identifier_constant(&name);
+        self.declare_variable();
+
+        self.emit_bytes(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.consume_match(&TokenType::Less) {
+            self.consume(&TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+            if self.previous.as_slice() == name.as_slice() {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // methods find the superclass through a local named `super`,
+            // scoped so sibling classes each get their own
+            self.begin_scope();
+            self.add_local(Token::synthetic("super"));
+            self.define_variable(0);
+
+            self.named_variable(&name, false);
+            self.emit_byte(OpCode::Inherit);
+            self.current_class().has_superclass = true;
+        }
+
+        // keep the class on the stack while its methods are attached to it
+        self.named_variable(&name, false);
+        self.consume(&TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(&TokenType::RightBrace) && !self.check(&TokenType::Eof) {
+            self.method();
+        }
+        self.consume(&TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop);
+
+        let class = self.classes.pop().expect("a class is being compiled");
+        if class.has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn current_class(&mut self) -> &mut ClassCompiler {
+        self.classes.last_mut().expect("a class is being compiled")
+    }
+
+    fn method(&mut self) {
+        self.consume(&TokenType::Identifier, "Expect method name.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name.as_slice() == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_bytes(OpCode::Method, constant);
+    }
+
+    pub fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // a function can refer to itself, so it's usable before its body is compiled
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.previous.clone();
+        let name = self.copy_string(name.as_slice());
+        self.compilers
+            .push(FunctionCompiler::new(function_type, Some(name)));
+        self.begin_scope();
+
+        self.consume(&TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                self.current_compiler().function.arity += 1;
+                if self.current_compiler().function.arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let param = self.parse_variable("Expect parameter name.");
+                self.define_variable(param);
+
+                if !self.consume_match(&TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&TokenType::RightParen, "Expect ')' after parameters.");
+
+        self.consume(&TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // no end_scope, the whole frame is discarded when the function returns
+        let (function, upvalues) = self.end_compilation();
+        let constant = self.make_constant(Value::from(function));
+        self.emit_bytes(OpCode::Closure, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    pub fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.consume_match(&TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+        self.consume(
+            &TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn parse_variable(&mut self, message: &str) -> u8 {
+        self.consume(&TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.scope_depth() > 0 {
+            return 0;
+        }
+
+        let name = self.previous.clone();
+        self.identifier_constant(&name)
+    }
+
+    /// Records a new local in the current scope.
+    /// Globals are late bound, so they aren't declared.
+    fn declare_variable(&mut self) {
+        let scope_depth = self.scope_depth();
+        if scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous.clone();
+        let mut duplicate = None;
+        for local in self.current_compiler().locals.iter().rev() {
+            if let Some(depth) = local.depth {
+                if depth < scope_depth {
+                    break;
+                }
+            }
+            if local.name.as_slice() == name.as_slice() {
+                duplicate = Some(local.name.clone());
+                break;
+            }
+        }
+        if let Some(declared) = duplicate {
+            let diagnostic = Diagnostic::error(
+                "Variable with this name already declared in this scope.",
+                name.line,
+                name.column,
+                name.span(),
+            )
+            .with_note(
+                "variable declared here",
+                declared.line,
+                declared.column,
+                declared.span(),
+            );
+            self.report(diagnostic);
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token<'c>) {
+        if self.current_compiler().locals.len() == LOCALS_MAX {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.current_compiler().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    /// Finds `name` among the locals of the function at `compiler_idx`
+    fn resolve_local(&mut self, compiler_idx: usize, name: &Token) -> Option<u8> {
+        let found = self.compilers[compiler_idx]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.as_slice() == name.as_slice())
+            .map(|(idx, local)| (idx, local.depth.is_none()));
+
+        match found {
+            Some((idx, uninitialized)) => {
+                if uninitialized {
+                    self.error("Can't read local variable in its own initializer.");
+                }
+                Some(idx as u8)
+            }
+            None => None,
+        }
+    }
+
+    /// Finds `name` in a function enclosing the one at `compiler_idx`,
+    /// threading it through every function in between as an upvalue
+    fn resolve_upvalue(&mut self, compiler_idx: usize, name: &Token) -> Option<u8> {
+        if compiler_idx == 0 {
+            return None;
+        }
+        let enclosing = compiler_idx - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler_idx, local, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(compiler_idx, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, compiler_idx: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.compilers[compiler_idx].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == UPVALUES_MAX {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let upvalues = &mut self.compilers[compiler_idx].upvalues;
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn mark_initialized(&mut self) {
+        let depth = self.scope_depth();
+        if depth == 0 {
+            return;
+        }
+        if let Some(local) = self.current_compiler().locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> u8 {
+        let s = self.copy_string(name.as_slice());
+        self.make_constant(Value::from(s))
+    }
+
+    fn define_variable(&mut self, global: u8) {
+        if self.scope_depth() > 0 {
+            // the initializer's value is already sitting in the local's slot
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_bytes(OpCode::DefineGlobal, global);
+    }
+
+    pub fn variable(&mut self, can_assign: bool) {
+        let name = self.previous.clone();
+        self.named_variable(&name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let current = self.compilers.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(current, name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(upvalue) = self.resolve_upvalue(current, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, upvalue)
+        } else {
+            let global = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, global)
+        };
+
+        if can_assign && self.consume_match(&TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(set_op, arg);
+        } else {
+            self.emit_bytes(get_op, arg);
+        }
+    }
+
+    pub fn statement(&mut self) {
+        if self.consume_match(&TokenType::Print) {
+            self.print_statement();
+        } else if self.consume_match(&TokenType::If) {
+            self.if_statement();
+        } else if self.consume_match(&TokenType::While) {
+            self.while_statement();
+        } else if self.consume_match(&TokenType::For) {
+            self.for_statement();
+        } else if self.consume_match(&TokenType::Return) {
+            self.return_statement();
+        } else if self.consume_match(&TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    pub fn block(&mut self) {
+        while !self.check(&TokenType::RightBrace) && !self.check(&TokenType::Eof) {
+            self.declaration();
+        }
+
+        self.consume(&TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.current_compiler().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current_compiler().scope_depth -= 1;
+        let scope_depth = self.scope_depth();
+
+        while let Some(local) = self.current_compiler().locals.last() {
+            match local.depth {
+                Some(depth) if depth <= scope_depth => break,
+                _ => {
+                    if local.is_captured {
+                        self.emit_byte(OpCode::CloseUpvalue);
+                    } else {
+                        self.emit_byte(OpCode::Pop);
+                    }
+                    self.current_compiler().locals.pop();
+                }
+            }
+        }
+    }
+
+    pub fn if_statement(&mut self) {
+        self.consume(&TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(&TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop);
+
+        if self.consume_match(&TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    pub fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+
+        self.consume(&TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(&TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop);
+    }
+
+    pub fn for_statement(&mut self) {
+        self.begin_scope();
+
+        self.consume(&TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.consume_match(&TokenType::Semicolon) {
+            // no initializer
+        } else if self.consume_match(&TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+
+        let mut exit_jump = None;
+        if !self.consume_match(&TokenType::Semicolon) {
+            self.expression();
+            self.consume(&TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop);
+        }
+
+        if !self.consume_match(&TokenType::RightParen) {
+            // the increment runs after the body, so jump over it now
+            // and loop back to it at the end of the body
+            let body_jump = self.emit_jump(OpCode::Jump);
+
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_byte(OpCode::Pop);
+            self.consume(&TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop);
+        }
+
+        self.end_scope();
+    }
+
+    pub fn return_statement(&mut self) {
+        if self.current_compiler().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.consume_match(&TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current_compiler().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(&TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return);
+        }
+    }
+
+    pub fn print_statement(&mut self) {
+        self.expression();
+        self.consume(&TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print);
+    }
+
+    pub fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(&TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop);
+    }
+
+    pub fn literal(&mut self) {
+        match self.previous.t {
+            TokenType::False => self.emit_byte(OpCode::False),
+            TokenType::Nil => self.emit_byte(OpCode::Nil),
+            TokenType::True => self.emit_byte(OpCode::True),
+            _ => panic!("Unrecognized literal"),
+        }
+    }
+
     pub fn grouping(&mut self) {
         self.expression();
         self.consume(&TokenType::RightParen, "Expect ')' after expression.");
     }
 
     pub fn unary(&mut self) {
-        let operator_type = self.previous.t.clone();
+        let operator = self.previous.clone();
 
         self.parse_precedence(Precedence::Unary);
 
-        match operator_type {
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
+        // errors from the instruction should point at the operator
+        match operator.t {
+            TokenType::Minus => self.emit_byte_at(OpCode::Negate, &operator),
+            TokenType::Bang => self.emit_byte_at(OpCode::Not, &operator),
             _ => panic!("Unrecognized operator"),
         }
     }
 
     pub fn binary(&mut self) {
-        let operator_type = self.previous.t.clone();
+        let operator = self.previous.clone();
 
-        let rule = get_rule(&operator_type);
+        let rule = get_rule(&operator.t);
         let p = Precedence::from_int(rule.precedence as u8 + 1).unwrap();
 
         self.parse_precedence(p);
 
-        match operator_type {
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
+        let (op, negate) = match operator.t {
+            TokenType::Plus => (OpCode::Add, false),
+            TokenType::Minus => (OpCode::Subtract, false),
+            TokenType::Star => (OpCode::Multiply, false),
+            TokenType::Slash => (OpCode::Divide, false),
+            TokenType::BangEqual => (OpCode::Equal, true),
+            TokenType::EqualEqual => (OpCode::Equal, false),
+            TokenType::Greater => (OpCode::Greater, false),
+            TokenType::GreaterEqual => (OpCode::Less, true),
+            TokenType::Less => (OpCode::Less, false),
+            TokenType::LessEqual => (OpCode::Greater, true),
             _ => panic!("Unrecognized operator"),
+        };
+        // errors from the instruction should point at the operator
+        self.emit_byte_at(op, &operator);
+        if negate {
+            self.emit_byte_at(OpCode::Not, &operator);
+        }
+    }
+
+    /// The right operand is only evaluated when the left one is truthy
+    pub fn and(&mut self) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    /// The right operand is only evaluated when the left one is falsey
+    pub fn or(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    pub fn call(&mut self) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::Call, arg_count);
+    }
+
+    pub fn dot(&mut self, can_assign: bool) {
+        self.consume(&TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous.clone();
+        let name = self.identifier_constant(&name);
+
+        if can_assign && self.consume_match(&TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(OpCode::SetProperty, name);
+        } else if self.consume_match(&TokenType::LeftParen) {
+            // a method call, which doesn't need a bound method
+            let arg_count = self.argument_list();
+            self.emit_bytes(OpCode::Invoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_bytes(OpCode::GetProperty, name);
+        }
+    }
+
+    pub fn this(&mut self) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
         }
+        // `this` is an ordinary local in slot zero of every method
+        self.variable(false);
+    }
+
+    pub fn super_(&mut self) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => (),
+        }
+
+        self.consume(&TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(&TokenType::Identifier, "Expect superclass method name.");
+        let name = self.previous.clone();
+        let name = self.identifier_constant(&name);
+
+        self.named_variable(&Token::synthetic("this"), false);
+        if self.consume_match(&TokenType::LeftParen) {
+            // call the method directly rather than binding it first
+            let arg_count = self.argument_list();
+            self.named_variable(&Token::synthetic("super"), false);
+            self.emit_bytes(OpCode::SuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(&Token::synthetic("super"), false);
+            self.emit_bytes(OpCode::GetSuper, name);
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.consume_match(&TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
 
+        let can_assign = precedence <= Precedence::Assignment;
         let rule = get_rule(&self.previous.t);
         match rule.prefix {
-            Some(prefix_rule) => self.parse_grammar_rule(&prefix_rule),
+            Some(prefix_rule) => self.parse_grammar_rule(&prefix_rule, can_assign),
             None => self.error("Expected expression."),
         }
 
@@ -156,18 +997,31 @@ impl Compiler<'c> {
             self.advance();
             let rule = get_rule(&self.previous.t);
             match rule.infix {
-                Some(infix_rule) => self.parse_grammar_rule(&infix_rule),
+                Some(infix_rule) => self.parse_grammar_rule(&infix_rule, can_assign),
                 None => self.error("Expected expression."),
             }
         }
+
+        if can_assign && self.consume_match(&TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
     }
 
-    fn parse_grammar_rule(&mut self, rule: &Grammar) {
+    fn parse_grammar_rule(&mut self, rule: &Grammar, can_assign: bool) {
         match rule {
+            Grammar::Variable => self.variable(can_assign),
             Grammar::Number => self.number(),
+            Grammar::Literal => self.literal(),
+            Grammar::String => self.string(),
+            Grammar::And => self.and(),
+            Grammar::Or => self.or(),
             Grammar::Unary => self.unary(),
             Grammar::Binary => self.binary(),
             Grammar::Grouping => self.grouping(),
+            Grammar::Call => self.call(),
+            Grammar::Dot => self.dot(can_assign),
+            Grammar::This => self.this(),
+            Grammar::Super => self.super_(),
         }
     }
 
@@ -193,6 +1047,18 @@ impl Compiler<'c> {
         }
     }
 
+    pub fn check(&self, token_type: &TokenType) -> bool {
+        self.current.t == *token_type
+    }
+
+    pub fn consume_match(&mut self, token_type: &TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
     fn error_at_current(&mut self, message: &str) {
         self.error_at(1, message)
     }
@@ -202,28 +1068,23 @@ impl Compiler<'c> {
     }
 
     fn error_at(&mut self, idx: usize, message: &str) {
-        if self.panic_mode {
-            return;
-        }
-        self.panic_mode = true;
         let token = match idx {
             0 => &self.previous,
             1 => &self.current,
             _ => panic!("bad index"),
         };
-        print!("[line {}] Error", token.line);
-
-        match token.t {
-            TokenType::Eof => print!("at end"),
-            TokenType::Error => {
-                () // do nothing
-            }
-            _ => print!("at '{}'", token.to_string()),
-        }
 
-        print!(": {}\n", message);
+        let diagnostic = Diagnostic::error(message, token.line, token.column, token.span());
+        self.report(diagnostic);
+    }
 
-        self.had_error = true;
+    /// Records an error unless we're still recovering from the last one
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.diagnostics.push(diagnostic);
     }
 }
 
@@ -263,9 +1124,18 @@ impl Precedence {
 
 enum Grammar {
     Grouping,
+    Call,
     Unary,
     Binary,
     Number,
+    String,
+    Literal,
+    Variable,
+    And,
+    Or,
+    Dot,
+    This,
+    Super,
 }
 
 struct ParseRule {
@@ -287,12 +1157,16 @@ impl ParseRule {
 fn get_rule(t: &TokenType) -> ParseRule {
     use crate::token::TokenType::*;
     match t {
-        LeftParen => ParseRule::new(Some(Grammar::Grouping), None, Precedence::Call), // TOKEN_LEFT_PAREN
+        LeftParen => ParseRule::new(
+            Some(Grammar::Grouping),
+            Some(Grammar::Call),
+            Precedence::Call,
+        ), // TOKEN_LEFT_PAREN
         RightParen => ParseRule::new(None, None, Precedence::None), // TOKEN_RIGHT_PAREN
         LeftBrace => ParseRule::new(None, None, Precedence::None),  // TOKEN_LEFT_BRACE
         RightBrace => ParseRule::new(None, None, Precedence::None), // TOKEN_RIGHT_BRACE
         Comma => ParseRule::new(None, None, Precedence::None),      // TOKEN_COMMA
-        Dot => ParseRule::new(None, None, Precedence::Call),        // TOKEN_DOT
+        Dot => ParseRule::new(None, Some(Grammar::Dot), Precedence::Call), // TOKEN_DOT
         Minus => ParseRule::new(
             Some(Grammar::Unary),
             Some(Grammar::Binary),
@@ -302,34 +1176,430 @@ fn get_rule(t: &TokenType) -> ParseRule {
         Semicolon => ParseRule::new(None, None, Precedence::None),  // TOKEN_SEMICOLON
         Slash => ParseRule::new(None, Some(Grammar::Binary), Precedence::Factor), // TOKEN_SLASH
         Star => ParseRule::new(None, Some(Grammar::Binary), Precedence::Factor), // TOKEN_STAR
-        Bang => ParseRule::new(None, None, Precedence::None),       // TOKEN_BANG
-        BangEqual => ParseRule::new(None, None, Precedence::Equality), // TOKEN_BANG_EQUAL
-        Equal => ParseRule::new(None, None, Precedence::None),      // TOKEN_EQUAL
-        EqualEqual => ParseRule::new(None, None, Precedence::Equality), // TOKEN_EQUAL_EQUAL
-        Greater => ParseRule::new(None, None, Precedence::Comparison), // TOKEN_GREATER
-        GreaterEqual => ParseRule::new(None, None, Precedence::Comparison), // TOKEN_GREATER_EQUAL
-        Less => ParseRule::new(None, None, Precedence::Comparison), // TOKEN_LESS
-        LessEqual => ParseRule::new(None, None, Precedence::Comparison), // TOKEN_LESS_EQUAL
-        Identifier => ParseRule::new(None, None, Precedence::None), // TOKEN_IDENTIFIER
-        String => ParseRule::new(None, None, Precedence::None),     // TOKEN_STRING
+        Bang => ParseRule::new(Some(Grammar::Unary), None, Precedence::None), // TOKEN_BANG
+        BangEqual => ParseRule::new(None, Some(Grammar::Binary), Precedence::Equality), // TOKEN_BANG_EQUAL
+        Equal => ParseRule::new(None, None, Precedence::None), // TOKEN_EQUAL
+        EqualEqual => ParseRule::new(None, Some(Grammar::Binary), Precedence::Equality), // TOKEN_EQUAL_EQUAL
+        Greater => ParseRule::new(None, Some(Grammar::Binary), Precedence::Comparison), // TOKEN_GREATER
+        GreaterEqual => ParseRule::new(None, Some(Grammar::Binary), Precedence::Comparison), // TOKEN_GREATER_EQUAL
+        Less => ParseRule::new(None, Some(Grammar::Binary), Precedence::Comparison), // TOKEN_LESS
+        LessEqual => ParseRule::new(None, Some(Grammar::Binary), Precedence::Comparison), // TOKEN_LESS_EQUAL
+        Identifier => ParseRule::new(Some(Grammar::Variable), None, Precedence::None), // TOKEN_IDENTIFIER
+        String => ParseRule::new(Some(Grammar::String), None, Precedence::None), // TOKEN_STRING
         Number => ParseRule::new(Some(Grammar::Number), None, Precedence::None), // TOKEN_NUMBER
-        And => ParseRule::new(None, None, Precedence::And),         // TOKEN_AND
-        Class => ParseRule::new(None, None, Precedence::None),      // TOKEN_CLASS
-        Else => ParseRule::new(None, None, Precedence::None),       // TOKEN_ELSE
-        False => ParseRule::new(None, None, Precedence::None),      // TOKEN_FALSE
-        Fun => ParseRule::new(None, None, Precedence::None),        // TOKEN_FUN
-        For => ParseRule::new(None, None, Precedence::None),        // TOKEN_FOR
-        If => ParseRule::new(None, None, Precedence::None),         // TOKEN_IF
-        Nil => ParseRule::new(None, None, Precedence::None),        // TOKEN_NIL
-        Or => ParseRule::new(None, None, Precedence::Or),           // TOKEN_OR
-        Print => ParseRule::new(None, None, Precedence::None),      // TOKEN_PRINT
-        Return => ParseRule::new(None, None, Precedence::None),     // TOKEN_RETURN
-        Super => ParseRule::new(None, None, Precedence::None),      // TOKEN_SUPER
-        This => ParseRule::new(None, None, Precedence::None),       // TOKEN_THIS
-        True => ParseRule::new(None, None, Precedence::None),       // TOKEN_TRUE
-        Var => ParseRule::new(None, None, Precedence::None),        // TOKEN_VAR
-        While => ParseRule::new(None, None, Precedence::None),      // TOKEN_WHILE
-        Error => ParseRule::new(None, None, Precedence::None),      // TOKEN_ERROR
-        Eof => ParseRule::new(None, None, Precedence::None),        // TOKEN_EOF
+        And => ParseRule::new(None, Some(Grammar::And), Precedence::And),        // TOKEN_AND
+        Class => ParseRule::new(None, None, Precedence::None),                   // TOKEN_CLASS
+        Else => ParseRule::new(None, None, Precedence::None),                    // TOKEN_ELSE
+        False => ParseRule::new(Some(Grammar::Literal), None, Precedence::None), // TOKEN_FALSE
+        Fun => ParseRule::new(None, None, Precedence::None),                     // TOKEN_FUN
+        For => ParseRule::new(None, None, Precedence::None),                     // TOKEN_FOR
+        If => ParseRule::new(None, None, Precedence::None),                      // TOKEN_IF
+        Nil => ParseRule::new(Some(Grammar::Literal), None, Precedence::None),   // TOKEN_NIL
+        Or => ParseRule::new(None, Some(Grammar::Or), Precedence::Or),           // TOKEN_OR
+        Print => ParseRule::new(None, None, Precedence::None),                   // TOKEN_PRINT
+        Return => ParseRule::new(None, None, Precedence::None),                  // TOKEN_RETURN
+        Super => ParseRule::new(Some(Grammar::Super), None, Precedence::None),   // TOKEN_SUPER
+        This => ParseRule::new(Some(Grammar::This), None, Precedence::None),     // TOKEN_THIS
+        True => ParseRule::new(Some(Grammar::Literal), None, Precedence::None),  // TOKEN_TRUE
+        Var => ParseRule::new(None, None, Precedence::None),                     // TOKEN_VAR
+        While => ParseRule::new(None, None, Precedence::None),                   // TOKEN_WHILE
+        Error => ParseRule::new(None, None, Precedence::None),                   // TOKEN_ERROR
+        Eof => ParseRule::new(None, None, Precedence::None),                     // TOKEN_EOF
     }
 }
+
+#[cfg(test)]
+fn op_codes(chunk: &Chunk) -> Vec<u8> {
+    chunk.code_iter().map(|(_, instr)| instr[0]).collect()
+}
+
+#[cfg(test)]
+fn script_chunk(function: &ObjRef) -> &Chunk {
+    &function.as_function().unwrap().chunk
+}
+
+#[test]
+fn compile_multiple_statements() {
+    let mut heap = Heap::new();
+    let script = compile("print 1 + 2;\n3;", &mut heap, &(), Options::default()).unwrap();
+    let chunk = script_chunk(&script);
+    assert_eq!(
+        op_codes(chunk),
+        vec![
+            OpCode::Constant as u8,
+            OpCode::Constant as u8,
+            OpCode::Add as u8,
+            OpCode::Print as u8,
+            OpCode::Constant as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ]
+    );
+}
+
+#[test]
+fn compile_invalid_assignment_target() {
+    assert!(compile(
+        "var a; var b; a + b = 1;",
+        &mut Heap::new(),
+        &(),
+        Options::default()
+    )
+    .is_err());
+}
+
+#[test]
+fn compile_local_in_own_initializer() {
+    assert!(compile("{ var a = a; }", &mut Heap::new(), &(), Options::default()).is_err());
+    assert!(compile(
+        "var a = 1; { var b = a; }",
+        &mut Heap::new(),
+        &(),
+        Options::default()
+    )
+    .is_ok());
+}
+
+#[test]
+fn compile_local_redeclared_in_scope() {
+    assert!(compile(
+        "{ var a = 1; var a = 2; }",
+        &mut Heap::new(),
+        &(),
+        Options::default()
+    )
+    .is_err());
+    assert!(compile(
+        "{ var a = 1; { var a = 2; } }",
+        &mut Heap::new(),
+        &(),
+        Options::default()
+    )
+    .is_ok());
+}
+
+#[test]
+fn compile_if_patches_jumps() {
+    let mut heap = Heap::new();
+    let source = "var a; if (a) print 1; else print 2;";
+    let script = compile(source, &mut heap, &(), Options::default()).unwrap();
+    let chunk = script_chunk(&script);
+    assert_eq!(
+        op_codes(chunk),
+        vec![
+            OpCode::Nil as u8,
+            OpCode::DefineGlobal as u8,
+            OpCode::GetGlobal as u8,
+            OpCode::JumpIfFalse as u8,
+            OpCode::Pop as u8,
+            OpCode::Constant as u8,
+            OpCode::Print as u8,
+            OpCode::Jump as u8,
+            OpCode::Pop as u8,
+            OpCode::Constant as u8,
+            OpCode::Print as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ]
+    );
+    // JumpIfFalse at 5 skips the then branch, landing on the else Pop at 15
+    assert_eq!(&chunk.code[5..8], &[OpCode::JumpIfFalse as u8, 0, 7]);
+    // Jump at 12 skips the else branch, landing on the implicit return at 19
+    assert_eq!(&chunk.code[12..15], &[OpCode::Jump as u8, 0, 4]);
+}
+
+#[test]
+fn compile_literals() {
+    let mut heap = Heap::new();
+    let script = compile("true; false; nil;", &mut heap, &(), Options::default()).unwrap();
+    let chunk = script_chunk(&script);
+    assert_eq!(
+        op_codes(chunk),
+        vec![
+            OpCode::True as u8,
+            OpCode::Pop as u8,
+            OpCode::False as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ]
+    );
+}
+
+#[test]
+fn compile_string_literal() {
+    let mut heap = Heap::new();
+    let script = compile("\"hello\";", &mut heap, &(), Options::default()).unwrap();
+    let s = script_chunk(&script).constants[0].as_obj().unwrap();
+    assert_eq!(s.as_string().unwrap().chars, "hello");
+}
+
+#[test]
+fn compile_deduplicates_string_constants() {
+    let source = "var a = \"a\"; a = \"a\" + \"b\"; print a;";
+    let mut heap = Heap::new();
+    let script = compile(source, &mut heap, &(), Options::default()).unwrap();
+    let chunk = script_chunk(&script);
+    // "a" doubles as the variable name, so only "a" and "b" are stored
+    assert_eq!(chunk.constants.len(), 2);
+}
+
+#[test]
+fn compile_function_declaration() {
+    let mut heap = Heap::new();
+    let script = compile(
+        "fun add(a, b) { return a + b; } print add(1, 2);",
+        &mut heap,
+        &(),
+        Options::default(),
+    )
+    .unwrap();
+    // constant 0 is the global's name
+    let function = script_chunk(&script).constants[1]
+        .as_obj()
+        .expect("expected a function constant");
+    let function = function.as_function().unwrap();
+    assert_eq!(function.arity, 2);
+    assert_eq!(function.upvalue_count, 0);
+    assert_eq!(function.name.unwrap().to_string(), "add");
+    assert_eq!(
+        op_codes(&function.chunk),
+        vec![
+            OpCode::GetLocal as u8,
+            OpCode::GetLocal as u8,
+            OpCode::Add as u8,
+            OpCode::Return as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ]
+    );
+}
+
+#[test]
+fn compile_closure_upvalues() {
+    let source = "
+        fun outer() {
+            var a = 1;
+            var b = 2;
+            fun middle() {
+                fun inner() { return a + b; }
+                return inner;
+            }
+            return middle;
+        }";
+    let mut heap = Heap::new();
+    let script = compile(source, &mut heap, &(), Options::default()).unwrap();
+
+    let find_function = |chunk: &Chunk| {
+        chunk
+            .constants
+            .iter()
+            .filter_map(|c| c.as_obj())
+            .find(|o| o.as_function().is_some())
+            .unwrap()
+    };
+    let outer = find_function(script_chunk(&script));
+    let middle = find_function(script_chunk(&outer));
+    let inner = find_function(script_chunk(&middle));
+
+    // middle captures a and b from outer's slots, inner captures middle's upvalues
+    let closure = |chunk: &Chunk| {
+        let offset = chunk
+            .code
+            .iter()
+            .position(|b| *b == OpCode::Closure as u8)
+            .unwrap();
+        chunk.code[offset + 2..offset + 6].to_vec()
+    };
+    assert_eq!(closure(script_chunk(&outer)), vec![1, 1, 1, 2]);
+    assert_eq!(closure(script_chunk(&middle)), vec![0, 0, 0, 1]);
+    assert_eq!(inner.as_function().unwrap().upvalue_count, 2);
+}
+
+#[test]
+fn compile_class_and_properties() {
+    let mut heap = Heap::new();
+    let source = "class A {} A().b = A().c;";
+    let script = compile(source, &mut heap, &(), Options::default()).unwrap();
+    assert_eq!(
+        op_codes(script_chunk(&script)),
+        vec![
+            OpCode::Class as u8,
+            OpCode::DefineGlobal as u8,
+            // the class is loaded while its methods are bound
+            OpCode::GetGlobal as u8,
+            OpCode::Pop as u8,
+            OpCode::GetGlobal as u8,
+            OpCode::Call as u8,
+            OpCode::GetGlobal as u8,
+            OpCode::Call as u8,
+            OpCode::GetProperty as u8,
+            OpCode::SetProperty as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ]
+    );
+
+    assert!(compile("class {}", &mut Heap::new(), &(), Options::default()).is_err());
+    assert!(compile("1 + A().b = 2;", &mut Heap::new(), &(), Options::default()).is_err());
+}
+
+#[test]
+fn compile_this_and_initializers() {
+    let mut heap = Heap::new();
+    let source = "class A { init() { this.a = 1; } }";
+    let script = compile(source, &mut heap, &(), Options::default()).unwrap();
+    let init = script_chunk(&script).constants[2]
+        .as_obj()
+        .expect("expected the initializer");
+    // `this` is slot zero, and initializers return it
+    assert_eq!(
+        op_codes(&init.as_function().unwrap().chunk),
+        vec![
+            OpCode::GetLocal as u8,
+            OpCode::Constant as u8,
+            OpCode::SetProperty as u8,
+            OpCode::Pop as u8,
+            OpCode::GetLocal as u8,
+            OpCode::Return as u8,
+        ]
+    );
+
+    let errors = [
+        "print this;",
+        "fun f() { return this; }",
+        "class A { init() { return 1; } }",
+    ];
+    for source in errors.iter() {
+        assert!(compile(source, &mut Heap::new(), &(), Options::default()).is_err());
+    }
+}
+
+#[test]
+fn compile_method_call_invokes() {
+    let mut heap = Heap::new();
+    let source = "var a; a.f(1, 2); a.g;";
+    let script = compile(source, &mut heap, &(), Options::default()).unwrap();
+    let chunk = script_chunk(&script);
+    assert_eq!(
+        op_codes(chunk),
+        vec![
+            OpCode::Nil as u8,
+            OpCode::DefineGlobal as u8,
+            OpCode::GetGlobal as u8,
+            OpCode::Constant as u8,
+            OpCode::Constant as u8,
+            OpCode::Invoke as u8,
+            OpCode::Pop as u8,
+            OpCode::GetGlobal as u8,
+            OpCode::GetProperty as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ]
+    );
+    // the argument count follows the method name
+    let invoke = chunk.code_iter().nth(5).unwrap().1;
+    assert_eq!(invoke[2], 2);
+}
+
+#[test]
+fn compile_super_errors() {
+    let errors = [
+        "class A < A {}",
+        "print super.f;",
+        "class A { f() { return super.f(); } }",
+        "class A {} class B < A { f() { return super; } }",
+    ];
+    for source in errors.iter() {
+        assert!(compile(source, &mut Heap::new(), &(), Options::default()).is_err());
+    }
+
+    let source = "class A { f() {} } class B < A { f() { super.f(); return super.f; } }";
+    assert!(compile(source, &mut Heap::new(), &(), Options::default()).is_ok());
+}
+
+#[test]
+fn compile_keeps_constants_alive_while_collecting() {
+    let source = "fun outer() { var a = \"a\"; fun inner() { return a + \"b\"; } } print \"c\";";
+    let mut heap = Heap::new();
+    heap.stress_gc = true;
+    let script = compile(source, &mut heap, &(), Options::default()).unwrap();
+    // every constant must still be a live object of the right kind
+    let outer = script_chunk(&script).constants[1]
+        .as_obj()
+        .expect("expected a function constant");
+    let outer = &outer.as_function().unwrap().chunk;
+    let a = outer.constants[0].as_obj().unwrap();
+    assert_eq!(a.as_string().unwrap().chars, "a");
+    assert!(outer.constants[1].as_obj().unwrap().as_function().is_some());
+    let s = script_chunk(&script).constants[2].as_obj().unwrap();
+    assert_eq!(s.as_string().unwrap().chars, "c");
+}
+
+#[test]
+fn compile_errors_are_diagnostics() {
+    let source = "var a = 1;\nprint a +;";
+    let diagnostics = match compile(source, &mut Heap::new(), &(), Options::default()) {
+        Err(InterpretError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("expected a compile error"),
+    };
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error("Expected expression.", 2, 10, 20..21)]
+    );
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 2:10] Error: Expected expression."
+    );
+}
+
+#[test]
+fn compile_duplicate_local_notes_the_declaration() {
+    let source = "{\n  var a = 1;\n  var a = 2;\n}";
+    let diagnostics = match compile(source, &mut Heap::new(), &(), Options::default()) {
+        Err(InterpretError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("expected a compile error"),
+    };
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            "Variable with this name already declared in this scope.",
+            3,
+            7,
+            21..22
+        )
+        .with_note("variable declared here", 2, 7, 8..9)]
+    );
+}
+
+#[test]
+fn compile_reports_every_independent_error() {
+    let source = "print 1 +;
+var = 2;
+fun f( { }
+print \"fine\";
+var b = 3 print b;
+{ var c = ; }";
+    let diagnostics = match compile(source, &mut Heap::new(), &(), Options::default()) {
+        Err(InterpretError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("expected compile errors"),
+    };
+    let lines: Vec<usize> = diagnostics.iter().map(|d| d.line).collect();
+    assert_eq!(lines, vec![1, 2, 3, 5, 6]);
+    assert_eq!(diagnostics[1].message, "Expect variable name.");
+}
+
+#[test]
+fn compile_return_outside_function() {
+    assert!(compile("return 1;", &mut Heap::new(), &(), Options::default()).is_err());
+}
+
+#[test]
+fn compile_statement_missing_semicolon() {
+    assert!(compile("print 1", &mut Heap::new(), &(), Options::default()).is_err());
+}
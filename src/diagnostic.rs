@@ -0,0 +1,290 @@
+use std::fmt;
+use std::fmt::Write;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// A problem found in the source, with enough location information
+/// for a caller to point at it however it likes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    /// Counted in characters from 1
+    pub column: usize,
+    /// The offending source text, as byte offsets into the source
+    pub span: Range<usize>,
+    /// Other places in the source that help explain the problem
+    pub notes: Vec<Note>,
+}
+
+/// A secondary message attached to another part of the source,
+/// like the earlier declaration a duplicate clashes with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: Range<usize>,
+}
+
+impl Diagnostic {
+    pub fn error(message: &str, line: usize, column: usize, span: Range<usize>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.to_owned(),
+            line,
+            column,
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_note(
+        mut self,
+        message: &str,
+        line: usize,
+        column: usize,
+        span: Range<usize>,
+    ) -> Diagnostic {
+        self.notes.push(Note {
+            message: message.to_owned(),
+            line,
+            column,
+            span,
+        });
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] {}: {}",
+            self.line, self.column, self.severity, self.message
+        )
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// A place in the source to underline, with an optional label after it
+struct Annotation<'d> {
+    line: usize,
+    column: usize,
+    span: &'d Range<usize>,
+    label: &'d str,
+    primary: bool,
+}
+
+/// Renders diagnostics the way rustc does: a header with the message,
+/// then each source line involved with its spans underlined.
+/// The primary span gets carets and notes get dashes with their message.
+pub struct Renderer<'a> {
+    source: &'a str,
+    color: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(source: &'a str) -> Renderer<'a> {
+        Renderer {
+            source,
+            color: false,
+        }
+    }
+
+    /// Whether to style the output with ANSI escapes, which only makes
+    /// sense when it's going to a terminal
+    pub fn with_color(mut self, color: bool) -> Renderer<'a> {
+        self.color = color;
+        self
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let mut annotations = vec![Annotation {
+            line: diagnostic.line,
+            column: diagnostic.column,
+            span: &diagnostic.span,
+            label: "",
+            primary: true,
+        }];
+        annotations.extend(diagnostic.notes.iter().map(|note| Annotation {
+            line: note.line,
+            column: note.column,
+            span: &note.span,
+            label: &note.message,
+            primary: false,
+        }));
+        // tokens the compiler makes up have no place in the source
+        annotations.retain(|annotation| annotation.line > 0);
+        annotations.sort_by_key(|annotation| annotation.line);
+
+        let severity_style = match diagnostic.severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        };
+        let width = annotations
+            .iter()
+            .map(|annotation| annotation.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = format!("{:width$} |", "", width = width);
+
+        let mut out = String::new();
+        let severity = diagnostic.severity.to_string().to_lowercase();
+        let header = format!(": {}", diagnostic.message);
+        self.push_line(
+            &mut out,
+            &format!(
+                "{}{}",
+                self.paint(severity_style, &severity),
+                self.paint(BOLD, &header)
+            ),
+        );
+        let arrow = format!("{:width$}-->", "", width = width);
+        let location = format!("{}:{}", diagnostic.line, diagnostic.column);
+        self.push_line(
+            &mut out,
+            &format!("{} {}", self.paint(BLUE, &arrow), location),
+        );
+        if annotations.is_empty() {
+            return out;
+        }
+        self.push_line(&mut out, &self.paint(BLUE, &gutter));
+
+        let mut last_line = None;
+        for annotation in &annotations {
+            let text = self.line_text(annotation.line);
+            if last_line != Some(annotation.line) {
+                if let Some(last) = last_line {
+                    if annotation.line > last + 1 {
+                        self.push_line(&mut out, &self.paint(BLUE, "..."));
+                    }
+                }
+                let number = format!("{:>width$} |", annotation.line, width = width);
+                self.push_line(&mut out, &format!("{} {}", self.paint(BLUE, &number), text));
+                last_line = Some(annotation.line);
+            }
+
+            // keep tabs so the underline lines up however they're displayed
+            let indent: String = text
+                .chars()
+                .take(annotation.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let underlined = self
+                .source
+                .get(annotation.span.clone())
+                .unwrap_or("")
+                .lines()
+                .next()
+                .map_or(0, |first| first.chars().count());
+            let (mark, style) = if annotation.primary {
+                ("^", severity_style)
+            } else {
+                ("-", BLUE)
+            };
+            let mut marker = mark.repeat(underlined.max(1));
+            if !annotation.label.is_empty() {
+                marker = format!("{} {}", marker, annotation.label);
+            }
+            self.push_line(
+                &mut out,
+                &format!(
+                    "{} {}{}",
+                    self.paint(BLUE, &gutter),
+                    indent,
+                    self.paint(style, &marker)
+                ),
+            );
+        }
+        out
+    }
+
+    /// The text of a line, counted from 1. The end of the input may be
+    /// reported on a line past the last one, which is empty.
+    fn line_text(&self, line: usize) -> &'a str {
+        self.source.lines().nth(line - 1).unwrap_or("")
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_owned()
+        }
+    }
+
+    fn push_line(&self, out: &mut String, line: &str) {
+        writeln!(out, "{}", line.trim_end()).unwrap();
+    }
+}
+
+#[test]
+fn renderer_underlines_the_span() {
+    let source = "var a = 1;\nprint a +;";
+    let diagnostic = Diagnostic::error("Expected expression.", 2, 10, 20..21);
+    assert_eq!(
+        Renderer::new(source).render(&diagnostic),
+        "error: Expected expression.
+ --> 2:10
+  |
+2 | print a +;
+  |          ^
+"
+    );
+}
+
+#[test]
+fn renderer_shows_notes_on_their_own_lines() {
+    let source = "{\n  var abc = 1;\n\n  var abc = 2;\n}";
+    let diagnostic = Diagnostic::error("Already declared.", 4, 7, 22..25).with_note(
+        "declared here",
+        2,
+        7,
+        8..11,
+    );
+    assert_eq!(
+        Renderer::new(source).render(&diagnostic),
+        "error: Already declared.
+ --> 4:7
+  |
+2 |   var abc = 1;
+  |       --- declared here
+...
+4 |   var abc = 2;
+  |       ^^^
+"
+    );
+}
+
+#[test]
+fn renderer_colors_only_when_asked() {
+    let diagnostic = Diagnostic::error("Expect ';' after value.", 1, 8, 7..7);
+    let plain = Renderer::new("print 1").render(&diagnostic);
+    assert!(!plain.contains('\x1b'));
+    assert!(plain.ends_with("  |        ^\n"));
+    let colored = Renderer::new("print 1")
+        .with_color(true)
+        .render(&diagnostic);
+    assert!(colored.starts_with(RED));
+}
@@ -1,9 +1,11 @@
-use crate::compiler::{compile, Options};
+use crate::compiler::Options;
 use crate::vm::{InterpretError, VM};
 pub fn interpret(source: &str) -> Result<(), InterpretError> {
-    println!("interpreting source {}", source);
+    interpret_in(&mut VM::new(), source)
+}
 
-    let chunk = compile(source, Options::debug())?;
-    let mut vm = VM::new(&chunk);
-    vm.run()
+/// Like `interpret`, but runs in an existing VM so that
+/// globals defined by earlier calls are still visible
+pub fn interpret_in(vm: &mut VM, source: &str) -> Result<(), InterpretError> {
+    vm.interpret(source, Options::default())
 }
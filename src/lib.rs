@@ -1,8 +1,10 @@
-#![feature(rust_2018_preview)]
-#![feature(nll)]
 pub mod chunk;
 pub mod compiler;
+pub mod diagnostic;
 pub mod interpreter;
+pub mod memory;
+pub mod native;
+pub mod object;
 pub mod scanner;
 pub mod token;
 pub mod value;
@@ -1,9 +1,13 @@
 use std::env;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{BufReader, IsTerminal};
+use std::process;
 
-use lox::interpreter::interpret;
+use lox::diagnostic::{Diagnostic, Renderer};
+use lox::interpreter::{interpret, interpret_in};
+use lox::vm::{InterpretError, VM};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -15,9 +19,13 @@ fn main() {
 
 fn repl() {
     println!("Lox Repl");
-    loop {
-        let input = rprompt::prompt_reply_stdout(">").unwrap();
-        interpret(&input).unwrap();
+    let mut vm = VM::new();
+    // stops at the end of input
+    while let Ok(input) = rprompt::prompt_reply_stdout(">") {
+        // an error only ends the current line, the VM is ready for the next one
+        if let Err(error) = interpret_in(&mut vm, &input) {
+            report(&error, &input, true);
+        }
     }
 }
 
@@ -26,5 +34,41 @@ fn interpret_file(path: &str) {
     let mut buf_reader = BufReader::new(file);
     let mut contents = String::new();
     buf_reader.read_to_string(&mut contents).unwrap();
-    interpret(&contents).unwrap();
+    if let Err(error) = interpret(&contents) {
+        report(&error, &contents, false);
+        let code = match error {
+            InterpretError::CompileError(_) => 65,
+            InterpretError::RuntimeError(_) => 70,
+        };
+        process::exit(code);
+    }
+}
+
+fn report(error: &InterpretError, source: &str, repl: bool) {
+    // everything below goes to stderr, so color only if that's a terminal
+    let renderer = Renderer::new(source).with_color(io::stderr().is_terminal());
+    match error {
+        InterpretError::CompileError(diagnostics) => {
+            for diagnostic in diagnostics {
+                eprintln!("{}", renderer.render(diagnostic));
+            }
+        }
+        // a REPL line can call a function an earlier line defined, whose
+        // positions are in that line, so only show code from the script itself
+        InterpretError::RuntimeError(error) if repl && error.trace.len() > 1 => {
+            eprintln!("{}", error)
+        }
+        InterpretError::RuntimeError(error) => {
+            let diagnostic = Diagnostic::error(
+                &error.message,
+                error.line as usize,
+                error.column as usize,
+                error.span.clone(),
+            );
+            eprint!("{}", renderer.render(&diagnostic));
+            for entry in &error.trace {
+                eprintln!("{}", entry);
+            }
+        }
+    }
 }
@@ -0,0 +1,314 @@
+use crate::chunk::Position;
+use crate::object::{Obj, ObjBox, ObjFunction, ObjRef, ObjString, UpvalueState};
+use crate::value::Value;
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ptr::NonNull;
+
+/// How much the heap may grow after a collection before the next one
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_FIRST_COLLECTION: usize = 1024 * 1024;
+
+/// Owns every object allocated while compiling and running a program.
+///
+/// Objects are reclaimed by a mark-sweep collector. The heap doesn't know
+/// its roots, so whoever allocates passes them to `collect_if_needed`.
+pub struct Heap {
+    objects: Vec<ObjRef>,
+    /// Every live string, so that equal strings share one object.
+    /// It doesn't keep strings alive: unmarked ones are dropped when collecting.
+    strings: HashSet<InternedString>,
+    /// Marked objects whose references haven't been traced yet
+    gray_stack: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Collect on every allocation, to flush out objects that aren't rooted
+    pub(crate) stress_gc: bool,
+}
+
+/// Something that holds references to heap objects,
+/// which a collection must not free
+pub trait Trace {
+    /// Marks every object directly referenced by `self`
+    fn trace(&self, heap: &mut Heap);
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            strings: HashSet::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_FIRST_COLLECTION,
+            stress_gc: false,
+        }
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += size_of(&obj);
+        let ptr = Box::into_raw(Box::new(ObjBox {
+            is_marked: Cell::new(false),
+            obj,
+        }));
+        let obj_ref = ObjRef::new(unsafe { NonNull::new_unchecked(ptr) });
+        self.objects.push(obj_ref);
+        obj_ref
+    }
+
+    /// Returns the interned string equal to `chars`, allocating it if needed
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        match self.strings.get(chars) {
+            Some(interned) => interned.0,
+            None => self.intern(chars.to_owned()),
+        }
+    }
+
+    /// Like `copy_string`, but takes ownership of `chars`
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        match self.strings.get(chars.as_str()) {
+            Some(interned) => interned.0,
+            None => self.intern(chars),
+        }
+    }
+
+    fn intern(&mut self, chars: String) -> ObjRef {
+        let s = self.alloc(Obj::String(ObjString::new(chars)));
+        self.strings.insert(InternedString(s));
+        s
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Counts memory an object took on after it was allocated, such as a
+    /// table that grew, so that it moves the heap towards a collection
+    pub(crate) fn grew(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+    }
+
+    /// Whether enough has been allocated since the last collection to start another
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    /// Collects if it's due, keeping `new`, which nothing references yet,
+    /// and everything reachable from `roots`
+    pub fn collect_if_needed(&mut self, new: ObjRef, roots: &dyn Trace) {
+        if self.should_collect() {
+            self.mark_object(new);
+            roots.trace(self);
+            self.collect();
+        }
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(obj) = value.as_obj() {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        if obj.is_marked() {
+            return;
+        }
+        obj.set_marked(true);
+        self.gray_stack.push(obj);
+    }
+
+    /// Frees every object that isn't reachable from the objects marked so far
+    pub fn collect(&mut self) {
+        self.trace_references();
+        self.strings.retain(|s| s.0.is_marked());
+        self.sweep();
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    fn blacken_object(&mut self, obj: ObjRef) {
+        match &*obj {
+            Obj::String(_) => (),
+            Obj::Function(function) => function.trace(self),
+            Obj::Closure(closure) => {
+                self.mark_object(closure.function);
+                for &upvalue in &closure.upvalues {
+                    self.mark_object(upvalue);
+                }
+            }
+            Obj::Upvalue(upvalue) => {
+                // an open upvalue's variable is on the stack, which is a root
+                if let UpvalueState::Closed(value) = upvalue.state.get() {
+                    self.mark_value(value);
+                }
+            }
+            Obj::Native(native) => self.mark_object(native.name),
+            Obj::Class(class) => {
+                self.mark_object(class.name);
+                for (&name, &method) in class.methods.borrow().iter() {
+                    self.mark_object(name);
+                    self.mark_value(method);
+                }
+            }
+            Obj::Instance(instance) => {
+                self.mark_object(instance.class);
+                for (&name, &value) in instance.fields.borrow().iter() {
+                    self.mark_object(name);
+                    self.mark_value(value);
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        let mut freed = 0;
+        self.objects.retain(|&obj| {
+            if obj.is_marked() {
+                obj.set_marked(false);
+                true
+            } else {
+                freed += size_of(&obj);
+                unsafe { drop(Box::from_raw(obj.as_ptr())) };
+                false
+            }
+        });
+        self.bytes_allocated -= freed;
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        self.strings.clear();
+        for obj in self.objects.drain(..) {
+            unsafe { drop(Box::from_raw(obj.as_ptr())) };
+        }
+    }
+}
+
+/// Roughly how many bytes `obj` takes up, including the memory it owns
+fn size_of(obj: &Obj) -> usize {
+    let owned = match obj {
+        Obj::String(s) => s.chars.capacity(),
+        Obj::Function(function) => {
+            let chunk = &function.chunk;
+            chunk.code.capacity()
+                + chunk.positions.capacity() * mem::size_of::<Position>()
+                + chunk.constants.capacity() * mem::size_of::<Value>()
+        }
+        Obj::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+        // tables grow after allocation, and `Heap::grew` counts it when they do
+        Obj::Class(class) => table_size(&class.methods.borrow()),
+        Obj::Instance(instance) => table_size(&instance.fields.borrow()),
+        Obj::Upvalue(_) | Obj::Native(_) | Obj::BoundMethod(_) => 0,
+    };
+    mem::size_of::<ObjBox>() + owned
+}
+
+/// Roughly how many bytes a table of fields or methods takes up
+pub(crate) fn table_size(table: &HashMap<ObjRef, Value>) -> usize {
+    table.capacity() * mem::size_of::<(ObjRef, Value)>()
+}
+
+impl Trace for ObjFunction {
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(name) = self.name {
+            heap.mark_object(name);
+        }
+        for &constant in &self.chunk.constants {
+            heap.mark_value(constant);
+        }
+    }
+}
+
+impl Trace for HashMap<ObjRef, Value> {
+    fn trace(&self, heap: &mut Heap) {
+        for (&key, &value) in self {
+            heap.mark_object(key);
+            heap.mark_value(value);
+        }
+    }
+}
+
+/// Nothing to mark, for compiling without a VM
+impl Trace for () {
+    fn trace(&self, _heap: &mut Heap) {}
+}
+
+/// An entry in the intern table. It hashes and compares by the string's
+/// contents so the table can be searched with a `&str`.
+struct InternedString(ObjRef);
+
+impl InternedString {
+    fn chars(&self) -> &str {
+        &self.0.as_string().expect("only strings are interned").chars
+    }
+}
+
+impl Borrow<str> for InternedString {
+    fn borrow(&self) -> &str {
+        self.chars()
+    }
+}
+
+impl PartialEq for InternedString {
+    fn eq(&self, other: &InternedString) -> bool {
+        self.chars() == other.chars()
+    }
+}
+
+impl Eq for InternedString {}
+
+impl Hash for InternedString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.chars().hash(state)
+    }
+}
+
+#[test]
+fn heap_interns_strings() {
+    let mut heap = Heap::new();
+    let a = heap.copy_string("lox");
+    let b = heap.take_string("lox".to_owned());
+    let c = heap.copy_string("clox");
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+    assert_eq!(heap.objects.len(), 2);
+}
+
+#[test]
+fn heap_collects_unreachable_objects() {
+    let mut heap = Heap::new();
+    let name = heap.copy_string("f");
+    let function = heap.alloc(Obj::Function(ObjFunction::new(Some(name))));
+    heap.copy_string("garbage");
+    let before = heap.bytes_allocated();
+
+    heap.mark_object(function);
+    heap.collect();
+
+    // the name survives because the function refers to it
+    assert_eq!(heap.objects, vec![name, function]);
+    assert!(heap.bytes_allocated() < before);
+    assert!(!name.is_marked() && !function.is_marked());
+    // the intern table doesn't keep the garbage string alive
+    assert_eq!(heap.strings.len(), 1);
+    assert!(heap.strings.contains("f"));
+}
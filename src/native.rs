@@ -0,0 +1,12 @@
+//! Builtin functions that every VM defines as globals
+use crate::memory::Heap;
+use crate::value::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch, for timing scripts
+pub fn clock(_heap: &mut Heap, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::from(now.as_secs_f64()))
+}
@@ -0,0 +1,276 @@
+use crate::chunk::Chunk;
+use crate::memory::Heap;
+use crate::value::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+/// A value that lives on the heap and is owned by a `Heap`
+pub enum Obj {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Native(ObjNative),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+}
+
+pub struct ObjString {
+    pub chars: String,
+}
+
+impl ObjString {
+    pub fn new(chars: String) -> ObjString {
+        ObjString { chars }
+    }
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// `None` for the top level script
+    pub name: Option<ObjRef>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>) -> ObjFunction {
+        ObjFunction {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+        }
+    }
+}
+
+/// A function implemented in Rust. It receives the heap, to allocate
+/// objects it returns, and its arguments as a slice. The heap doesn't
+/// collect during the call. An `Err` becomes a runtime error in the
+/// calling script.
+pub type NativeFn = fn(&mut Heap, &[Value]) -> Result<Value, String>;
+
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl ObjNative {
+    pub fn new(name: ObjRef, arity: usize, function: NativeFn) -> ObjNative {
+        ObjNative {
+            name,
+            arity,
+            function,
+        }
+    }
+}
+
+/// A function together with the variables it captured when it was created
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+impl ObjClosure {
+    pub fn new(function: ObjRef, upvalues: Vec<ObjRef>) -> ObjClosure {
+        ObjClosure { function, upvalues }
+    }
+
+    pub fn function(&self) -> &ObjFunction {
+        self.function
+            .as_function()
+            .expect("closures wrap functions")
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum UpvalueState {
+    /// The variable is still on the stack, in this slot
+    Open(usize),
+    /// The variable's scope has ended and the upvalue holds it
+    Closed(Value),
+}
+
+/// A variable captured by a closure
+pub struct ObjUpvalue {
+    pub state: Cell<UpvalueState>,
+}
+
+impl ObjUpvalue {
+    pub fn new(slot: usize) -> ObjUpvalue {
+        ObjUpvalue {
+            state: Cell::new(UpvalueState::Open(slot)),
+        }
+    }
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    /// Closures keyed by interned method name
+    pub methods: RefCell<HashMap<ObjRef, Value>>,
+}
+
+impl ObjClass {
+    pub fn new(name: ObjRef) -> ObjClass {
+        ObjClass {
+            name,
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    /// Keyed by interned name, like globals
+    pub fields: RefCell<HashMap<ObjRef, Value>>,
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> ObjInstance {
+        ObjInstance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn class(&self) -> &ObjClass {
+        self.class.as_class().expect("instances belong to classes")
+    }
+}
+
+/// A method closure paired with the instance it was accessed on,
+/// so that `this` still refers to that instance when it's called later
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: Value, method: ObjRef) -> ObjBoundMethod {
+        ObjBoundMethod { receiver, method }
+    }
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Obj::String(s) => write!(f, "{}", s.chars),
+            Obj::Function(function) => match function.name {
+                Some(name) => write!(f, "<fn {}>", name),
+                None => write!(f, "<script>"),
+            },
+            Obj::Closure(closure) => write!(f, "{}", closure.function),
+            Obj::Upvalue(_) => write!(f, "upvalue"),
+            Obj::Native(_) => write!(f, "<native fn>"),
+            Obj::Class(class) => write!(f, "{}", class.name),
+            Obj::Instance(instance) => write!(f, "{} instance", instance.class().name),
+            Obj::BoundMethod(bound) => write!(f, "{}", bound.method),
+        }
+    }
+}
+
+/// An allocation made by a `Heap`: an object and the collector's mark bit
+pub(crate) struct ObjBox {
+    pub(crate) is_marked: Cell<bool>,
+    pub(crate) obj: Obj,
+}
+
+/// A pointer to an object allocated by a `Heap`.
+/// It stays valid for as long as the heap that allocated it keeps the object alive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjRef {
+    ptr: NonNull<ObjBox>,
+}
+
+impl ObjRef {
+    pub(crate) fn new(ptr: NonNull<ObjBox>) -> ObjRef {
+        ObjRef { ptr }
+    }
+
+    pub(crate) fn as_ptr(self) -> *mut ObjBox {
+        self.ptr.as_ptr()
+    }
+
+    pub(crate) fn is_marked(self) -> bool {
+        unsafe { self.ptr.as_ref() }.is_marked.get()
+    }
+
+    pub(crate) fn set_marked(self, is_marked: bool) {
+        unsafe { self.ptr.as_ref() }.is_marked.set(is_marked)
+    }
+
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match &**self {
+            Obj::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&ObjFunction> {
+        match &**self {
+            Obj::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&ObjClosure> {
+        match &**self {
+            Obj::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_native(&self) -> Option<&ObjNative> {
+        match &**self {
+            Obj::Native(native) => Some(native),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self) -> Option<&ObjUpvalue> {
+        match &**self {
+            Obj::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&ObjClass> {
+        match &**self {
+            Obj::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&ObjInstance> {
+        match &**self {
+            Obj::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub fn as_bound_method(&self) -> Option<&ObjBoundMethod> {
+        match &**self {
+            Obj::BoundMethod(bound) => Some(bound),
+            _ => None,
+        }
+    }
+}
+
+impl Deref for ObjRef {
+    type Target = Obj;
+
+    fn deref(&self) -> &Obj {
+        unsafe { &self.ptr.as_ref().obj }
+    }
+}
+
+impl fmt::Display for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", **self)
+    }
+}
@@ -1,17 +1,29 @@
 use crate::token::{Token, TokenType};
+
+/// Walks the source a character at a time. `start` and `current` are
+/// byte offsets, always on character boundaries, so tokens can slice the
+/// source directly; columns count characters for people reading them.
 pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: usize,
+    /// Characters consumed so far on the current line
+    column: usize,
+    /// The position of the token being scanned
+    start_line: usize,
+    start_column: usize,
     source: &'a str,
 }
 
-impl Scanner<'a> {
-    pub fn new(source: &'a str) -> Scanner {
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Scanner<'a> {
         Scanner {
             start: 0,
             current: 0,
             line: 1,
+            column: 0,
+            start_line: 1,
+            start_column: 1,
             source,
         }
     }
@@ -25,11 +37,11 @@ impl Scanner<'a> {
     }
 
     pub fn peek(&self) -> char {
-        self.source.chars().nth(self.current).unwrap_or('\0')
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     pub fn peek_next(&self) -> Option<char> {
-        self.source.chars().nth(self.current + 1)
+        self.source[self.current..].chars().nth(1)
     }
 
     pub fn is_at_end(&self) -> bool {
@@ -39,6 +51,8 @@ impl Scanner<'a> {
     pub fn scan_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column + 1;
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
         }
@@ -92,14 +106,13 @@ impl Scanner<'a> {
 
     fn skip_whitespace(&mut self) {
         loop {
-            println!("skip whitespace loop");
             match self.peek() {
                 ' ' | '\r' | '\t' => {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
                 '/' => {
                     if let Some('/') = self.peek_next() {
@@ -115,9 +128,17 @@ impl Scanner<'a> {
         }
     }
 
+    /// Call after consuming a newline
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 0;
+    }
+
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        let c = self.peek();
+        self.current += c.len_utf8();
+        self.column += 1;
+        c
     }
 
     pub fn consume_match(&mut self, expected: char) -> bool {
@@ -142,7 +163,8 @@ impl Scanner<'a> {
             t,
             start: self.start,
             length: self.current - self.start,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             source: &self.source[self.start..self.current],
         }
     }
@@ -150,9 +172,10 @@ impl Scanner<'a> {
     fn error_token(&mut self, message: &'static str) -> Token<'static> {
         Token {
             t: TokenType::Error,
-            start: 0,
-            length: message.len(),
-            line: self.line,
+            start: self.start,
+            length: self.current - self.start,
+            line: self.start_line,
+            column: self.start_column,
             source: message,
         }
     }
@@ -161,10 +184,9 @@ impl Scanner<'a> {
 
     pub fn string(&mut self) -> Token<'a> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -181,7 +203,7 @@ impl Scanner<'a> {
             self.advance();
         }
 
-        if self.peek() == '.' && is_digit(self.peek_next().unwrap()) {
+        if self.peek() == '.' && is_digit(self.peek_next().unwrap_or('\0')) {
             // consume the '.'
             self.advance();
         }
@@ -203,40 +225,53 @@ impl Scanner<'a> {
         self.make_token(t)
     }
 
+    /// Picks out keywords by switching on their leading letters, like the
+    /// trie in clox, so most identifiers are ruled out after one byte
     pub fn identifier_type(&self) -> TokenType {
-        match self.current_str() {
-            "and" => TokenType::And,
-            "class" => TokenType::Class,
-            "else" => TokenType::Else,
-            "if" => TokenType::If,
-            "false" => TokenType::False,
-            "for" => TokenType::For,
-            "fun" => TokenType::Fun,
-            "or" => TokenType::Or,
-            "print" => TokenType::Print,
-            "return" => TokenType::Return,
-            "super" => TokenType::Super,
-            "this" => TokenType::This,
-            "true" => TokenType::True,
-            "var" => TokenType::Var,
-            "while" => TokenType::While,
+        let lexeme = self.current_str().as_bytes();
+        match lexeme[0] {
+            b'a' => self.check_keyword(1, "nd", TokenType::And),
+            b'c' => self.check_keyword(1, "lass", TokenType::Class),
+            b'e' => self.check_keyword(1, "lse", TokenType::Else),
+            b'f' if lexeme.len() > 1 => match lexeme[1] {
+                b'a' => self.check_keyword(2, "lse", TokenType::False),
+                b'o' => self.check_keyword(2, "r", TokenType::For),
+                b'u' => self.check_keyword(2, "n", TokenType::Fun),
+                _ => TokenType::Identifier,
+            },
+            b'i' => self.check_keyword(1, "f", TokenType::If),
+            b'n' => self.check_keyword(1, "il", TokenType::Nil),
+            b'o' => self.check_keyword(1, "r", TokenType::Or),
+            b'p' => self.check_keyword(1, "rint", TokenType::Print),
+            b'r' => self.check_keyword(1, "eturn", TokenType::Return),
+            b's' => self.check_keyword(1, "uper", TokenType::Super),
+            b't' if lexeme.len() > 1 => match lexeme[1] {
+                b'h' => self.check_keyword(2, "is", TokenType::This),
+                b'r' => self.check_keyword(2, "ue", TokenType::True),
+                _ => TokenType::Identifier,
+            },
+            b'v' => self.check_keyword(1, "ar", TokenType::Var),
+            b'w' => self.check_keyword(1, "hile", TokenType::While),
             _ => TokenType::Identifier,
         }
     }
+
+    /// The keyword `t` if the rest of the lexeme from `start` is `rest`
+    fn check_keyword(&self, start: usize, rest: &str, t: TokenType) -> TokenType {
+        if &self.current_str()[start..] == rest {
+            t
+        } else {
+            TokenType::Identifier
+        }
+    }
 }
 
 pub fn is_digit(c: char) -> bool {
-    match c {
-        '0'...'9' => true,
-        _ => false,
-    }
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    match c {
-        's'...'z' | 'S'...'Z' | '_' => true,
-        _ => false,
-    }
+    matches!(c, 'a'..='z' | 'A'..='Z' | '_')
 }
 
 #[test]
@@ -285,6 +320,117 @@ fn scanner_scan_string() {
     assert_eq!(t.to_string(), "\"Hey\"");
 }
 
+#[test]
+fn scanner_scan_identifier_starting_with_any_letter() {
+    let mut s = Scanner::new("print Apple");
+    assert_eq!(s.scan_token().t, TokenType::Print);
+    let t = s.scan_token();
+    assert_eq!((&t.t, t.as_slice()), (&TokenType::Identifier, "Apple"));
+}
+
+#[test]
+fn scanner_scan_literal_keywords() {
+    let mut s = Scanner::new("true false nil");
+    assert_eq!(s.scan_token().t, TokenType::True);
+    assert_eq!(s.scan_token().t, TokenType::False);
+    assert_eq!(s.scan_token().t, TokenType::Nil);
+}
+
+#[test]
+fn scanner_scan_every_keyword() {
+    let mut s = Scanner::new(
+        "and class else false for fun if nil or print return super this true var while",
+    );
+    let expected = vec![
+        TokenType::And,
+        TokenType::Class,
+        TokenType::Else,
+        TokenType::False,
+        TokenType::For,
+        TokenType::Fun,
+        TokenType::If,
+        TokenType::Nil,
+        TokenType::Or,
+        TokenType::Print,
+        TokenType::Return,
+        TokenType::Super,
+        TokenType::This,
+        TokenType::True,
+        TokenType::Var,
+        TokenType::While,
+    ];
+    for t in expected {
+        assert_eq!(s.scan_token().t, t);
+    }
+    assert_eq!(s.scan_token().t, TokenType::Eof);
+}
+
+#[test]
+fn scanner_scan_identifiers() {
+    // prefixes and extensions of keywords, and names starting with every kind of letter
+    let source = "f t n fo fork thisx truth an classy _private camelCase Zed bar2 a_1";
+    let mut s = Scanner::new(source);
+    for name in source.split(' ') {
+        let t = s.scan_token();
+        assert_eq!((&t.t, t.as_slice()), (&TokenType::Identifier, name));
+    }
+    assert_eq!(s.scan_token().t, TokenType::Eof);
+}
+
+#[test]
+fn scanner_tracks_columns_and_spans() {
+    let mut s = Scanner::new("var a;\n  print \"two\nlines\" @");
+    let columns: Vec<(usize, usize)> = (0..3)
+        .map(|_| s.scan_token())
+        .map(|t| (t.line, t.column))
+        .collect();
+    assert_eq!(columns, vec![(1, 1), (1, 5), (1, 6)]);
+
+    let print = s.scan_token();
+    assert_eq!((print.line, print.column, print.span()), (2, 3, 9..14));
+    // a string spanning lines is positioned at its start
+    let string = s.scan_token();
+    assert_eq!((string.line, string.column, string.span()), (2, 9, 15..26));
+
+    // error tokens keep the position of the offending text
+    let error = s.scan_token();
+    assert_eq!(error.t, TokenType::Error);
+    assert_eq!((error.line, error.column, error.span()), (3, 8, 27..28));
+}
+
+#[test]
+fn scanner_handles_multi_byte_strings() {
+    let mut s = Scanner::new("print \"héllo 🦀\"; x");
+    s.scan_token();
+    let string = s.scan_token();
+    assert_eq!(string.t, TokenType::String);
+    assert_eq!(string.as_slice(), "\"héllo 🦀\"");
+    assert_eq!(string.span(), 6..19);
+    // columns count characters, not bytes
+    let semicolon = s.scan_token();
+    assert_eq!((semicolon.column, semicolon.span()), (16, 19..20));
+    assert_eq!(s.scan_token().column, 18);
+    assert_eq!(s.scan_token().t, TokenType::Eof);
+}
+
+#[test]
+fn scanner_rejects_multi_byte_characters_outside_strings() {
+    let mut s = Scanner::new("a ≠ b");
+    s.scan_token();
+    let error = s.scan_token();
+    assert_eq!(error.t, TokenType::Error);
+    assert_eq!((error.column, error.span()), (3, 2..5));
+    let b = s.scan_token();
+    assert_eq!((b.as_slice(), b.column), ("b", 5));
+}
+
+#[test]
+fn scanner_number_at_end_of_input() {
+    let mut s = Scanner::new("1.");
+    assert_eq!(s.scan_token().as_slice(), "1");
+    assert_eq!(s.scan_token().t, TokenType::Dot);
+}
+
 #[test]
 fn scanner_current_str() {
     let mut s = Scanner::new("{}");
@@ -1,3 +1,6 @@
+use std::fmt;
+use std::ops::Range;
+
 #[allow(dead_code)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
@@ -55,14 +58,25 @@ pub enum TokenType {
 pub struct Token<'a> {
     pub start: usize,
     pub length: usize,
+    /// The line the token starts on
     pub line: usize,
+    /// Counted in characters from 1
+    pub column: usize,
     pub t: TokenType,
     pub source: &'a str,
 }
 
-impl Token<'a> {
-    pub fn to_string(&self) -> String {
-        self.source.to_owned()
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl<'a> Token<'a> {
+    /// Where the token is in the source. For an error token `source` is
+    /// the error message, but this is still the text that caused it.
+    pub fn span(&self) -> Range<usize> {
+        self.start..self.start + self.length
     }
 
     ///
@@ -71,11 +85,24 @@ impl Token<'a> {
         self.source
     }
 
+    /// A token for a name the compiler introduces itself, like the `this` in methods
+    pub fn synthetic(text: &'static str) -> Token<'static> {
+        Token {
+            start: 0,
+            length: text.len(),
+            line: 0,
+            column: 0,
+            t: TokenType::Identifier,
+            source: text,
+        }
+    }
+
     pub fn empty() -> Token<'static> {
         Token {
             start: 0,
             length: 0,
             line: 0,
+            column: 0,
             t: TokenType::Error,
             source: "Empty token",
         }
@@ -1,18 +1,179 @@
 use std::fmt;
-#[derive(Clone, Copy)]
-pub enum Value {
-    Bool(bool),
-    Nil,
-    Number(f64),
+
+#[cfg(feature = "nan-boxing")]
+pub use self::nan_boxed::Value;
+#[cfg(not(feature = "nan-boxing"))]
+pub use self::tagged::Value;
+
+// Both representations offer the same constructors and accessors,
+// so code outside this module never depends on which one is in use.
+
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged {
+    use crate::object::ObjRef;
+
+    #[derive(Clone, Copy)]
+    pub enum Value {
+        Bool(bool),
+        Nil,
+        Number(f64),
+        Obj(ObjRef),
+    }
+
+    impl Value {
+        pub fn nil() -> Value {
+            Value::Nil
+        }
+
+        pub fn is_nil(self) -> bool {
+            matches!(self, Value::Nil)
+        }
+
+        pub fn as_bool(self) -> Option<bool> {
+            match self {
+                Value::Bool(b) => Some(b),
+                _ => None,
+            }
+        }
+
+        pub fn as_number(self) -> Option<f64> {
+            match self {
+                Value::Number(n) => Some(n),
+                _ => None,
+            }
+        }
+
+        pub fn as_obj(self) -> Option<ObjRef> {
+            match self {
+                Value::Obj(o) => Some(o),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<f64> for Value {
+        fn from(n: f64) -> Value {
+            Value::Number(n)
+        }
+    }
+
+    impl From<ObjRef> for Value {
+        fn from(o: ObjRef) -> Value {
+            Value::Obj(o)
+        }
+    }
+
+    impl From<bool> for Value {
+        fn from(b: bool) -> Value {
+            Value::Bool(b)
+        }
+    }
+}
+
+/// Every value fits in the 64 bits of a double. Numbers are stored as
+/// themselves; anything else is a quiet NaN, which arithmetic never
+/// produces, with the sign bit set for objects and the low bits holding
+/// either the object's address or a tag for nil, true and false.
+#[cfg(feature = "nan-boxing")]
+mod nan_boxed {
+    use crate::object::ObjRef;
+    use std::ptr::NonNull;
+
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    const NIL: u64 = QNAN | TAG_NIL;
+    const FALSE: u64 = QNAN | TAG_FALSE;
+    const TRUE: u64 = QNAN | TAG_TRUE;
+
+    #[derive(Clone, Copy)]
+    pub struct Value(u64);
+
+    impl Value {
+        pub fn nil() -> Value {
+            Value(NIL)
+        }
+
+        pub fn is_nil(self) -> bool {
+            self.0 == NIL
+        }
+
+        pub fn as_bool(self) -> Option<bool> {
+            match self.0 {
+                TRUE => Some(true),
+                FALSE => Some(false),
+                _ => None,
+            }
+        }
+
+        pub fn as_number(self) -> Option<f64> {
+            if self.0 & QNAN == QNAN {
+                None
+            } else {
+                Some(f64::from_bits(self.0))
+            }
+        }
+
+        pub fn as_obj(self) -> Option<ObjRef> {
+            if self.0 & (QNAN | SIGN_BIT) != QNAN | SIGN_BIT {
+                return None;
+            }
+            let ptr = (self.0 & !(QNAN | SIGN_BIT)) as *mut _;
+            NonNull::new(ptr).map(ObjRef::new)
+        }
+    }
+
+    impl From<f64> for Value {
+        fn from(n: f64) -> Value {
+            Value(n.to_bits())
+        }
+    }
+
+    impl From<ObjRef> for Value {
+        fn from(o: ObjRef) -> Value {
+            Value(SIGN_BIT | QNAN | o.as_ptr() as u64)
+        }
+    }
+
+    impl From<bool> for Value {
+        fn from(b: bool) -> Value {
+            Value(if b { TRUE } else { FALSE })
+        }
+    }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self {
-            Value::Bool(b) => write!(f, "{}", b),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Nil => write!(f, "nil"),
+        if let Some(b) = self.as_bool() {
+            write!(f, "{}", b)
+        } else if let Some(n) = self.as_number() {
+            write!(f, "{}", n)
+        } else if let Some(o) = self.as_obj() {
+            write!(f, "{}", o)
+        } else {
+            write!(f, "nil")
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        // compare numbers as numbers, so NaN != NaN whatever the representation
+        if let (Some(a), Some(b)) = (self.as_number(), other.as_number()) {
+            return a == b;
+        }
+        if let (Some(a), Some(b)) = (self.as_bool(), other.as_bool()) {
+            return a == b;
         }
+        // strings are interned, so equal strings are the same object
+        if let (Some(a), Some(b)) = (self.as_obj(), other.as_obj()) {
+            return a == b;
+        }
+        self.is_nil() && other.is_nil()
     }
 }
 
@@ -21,3 +182,60 @@ pub type ValueArray = Vec<Value>;
 pub fn print_value(v: Value) {
     print!("{}", v);
 }
+
+#[test]
+fn value_equality() {
+    assert!(Value::nil() == Value::nil());
+    assert!(Value::from(true) == Value::from(true));
+    assert!(Value::from(1.5) == Value::from(1.5));
+    assert!(Value::from(false) != Value::nil());
+    assert!(Value::from(0.0) != Value::from(false));
+    assert!(Value::from(f64::NAN) != Value::from(f64::NAN));
+}
+
+#[test]
+fn value_string_equality() {
+    let mut heap = crate::memory::Heap::new();
+    let a = heap.copy_string("lox");
+    let b = heap.take_string("lox".to_owned());
+    let c = heap.copy_string("clox");
+    assert!(Value::from(a) == Value::from(b));
+    assert!(Value::from(a) != Value::from(c));
+    assert!(Value::from(a) != Value::nil());
+}
+
+#[test]
+fn value_accessors_round_trip() {
+    let mut heap = crate::memory::Heap::new();
+    let s = heap.copy_string("lox");
+    let values = [
+        Value::nil(),
+        Value::from(true),
+        Value::from(false),
+        Value::from(-2.5),
+        Value::from(f64::NAN),
+        Value::from(s),
+    ];
+    for (i, value) in values.iter().enumerate() {
+        let kinds = [
+            value.is_nil(),
+            value.as_bool().is_some(),
+            value.as_number().is_some(),
+            value.as_obj().is_some(),
+        ];
+        assert_eq!(kinds.iter().filter(|&&k| k).count(), 1, "value {}", i);
+    }
+    assert_eq!(values[1].as_bool(), Some(true));
+    assert_eq!(values[2].as_bool(), Some(false));
+    assert_eq!(values[3].as_number(), Some(-2.5));
+    assert!(values[4].as_number().unwrap().is_nan());
+    assert_eq!(values[5].as_obj(), Some(s));
+    assert_eq!(values[5].to_string(), "lox");
+    assert_eq!(values[0].to_string(), "nil");
+}
+
+#[cfg(feature = "nan-boxing")]
+#[test]
+fn value_fits_in_a_word() {
+    assert_eq!(std::mem::size_of::<Value>(), 8);
+}
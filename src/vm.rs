@@ -1,24 +1,81 @@
 use crate::chunk::{Chunk, OpCode};
+use crate::compiler::{compile, Options};
+use crate::diagnostic::Diagnostic;
+use crate::memory::{table_size, Heap, Trace};
+use crate::native;
+use crate::object::{
+    NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative,
+    ObjRef, ObjUpvalue, UpvalueState,
+};
 use crate::value::{print_value, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::ops::Range;
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * 256;
 
 #[derive(Debug)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
 }
 
+/// An error raised while running a script, after which the VM's stack is reset
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The position of the instruction that failed
+    pub line: u32,
+    pub column: u32,
+    /// The source text the failing instruction was compiled from
+    pub span: Range<usize>,
+    /// The calls that were active, innermost first
+    pub trace: Vec<TraceEntry>,
+}
+
+#[derive(Debug)]
+pub struct TraceEntry {
+    pub line: u32,
+    pub column: u32,
+    /// `None` for the top level script
+    pub function: Option<String>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for entry in &self.trace {
+            write!(f, "\n{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+/// Holds temporaries as well as local variables; a local's slot
+/// is its index from the bottom of the stack.
 struct OperandStack {
-    stack: [Value; STACK_MAX],
+    /// Always STACK_MAX long. It lives on the heap since it's far too big
+    /// to build on the native stack. Capping frames doesn't bound it, since
+    /// a frame can hold more than 256 values; `VM::push` checks it isn't full.
+    stack: Box<[Value]>,
     stack_top: usize,
 }
 
 impl OperandStack {
     pub fn new() -> OperandStack {
         OperandStack {
-            stack: [Value::Number(0.0); 256],
+            stack: vec![Value::nil(); STACK_MAX].into_boxed_slice(),
             stack_top: 0,
         }
     }
@@ -33,6 +90,28 @@ impl OperandStack {
         self.stack[self.stack_top]
     }
 
+    pub fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack_top - 1 - distance]
+    }
+
+    pub fn get(&self, slot: usize) -> Value {
+        self.stack[slot]
+    }
+
+    pub fn set(&mut self, slot: usize, value: Value) {
+        self.stack[slot] = value;
+    }
+
+    /// The `n` values on top of the stack, from the deepest up
+    pub fn top(&self, n: usize) -> &[Value] {
+        &self.stack[self.stack_top - n..self.stack_top]
+    }
+
+    /// Discards every value above the first `len`
+    pub fn truncate(&mut self, len: usize) {
+        self.stack_top = len;
+    }
+
     pub fn len(&self) -> usize {
         self.stack_top
     }
@@ -48,30 +127,212 @@ impl fmt::Debug for OperandStack {
     }
 }
 
-pub struct VM<'v> {
-    chunk: &'v Chunk,
+/// An ongoing function call
+struct CallFrame {
+    closure: ObjRef,
     ip: usize,
+    /// Index of the frame's first stack slot, which holds the closure itself
+    slots: usize,
+}
+
+impl CallFrame {
+    fn closure(&self) -> &ObjClosure {
+        self.closure
+            .as_closure()
+            .expect("call frames hold closures")
+    }
+
+    fn function(&self) -> &ObjFunction {
+        self.closure().function()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.function().chunk
+    }
+}
+
+/// The objects a running VM can reach directly
+struct Roots<'v> {
+    stack: &'v [Value],
+    frames: &'v [CallFrame],
+    open_upvalues: &'v [ObjRef],
+    globals: &'v HashMap<ObjRef, Value>,
+    init_string: ObjRef,
+}
+
+impl Trace for Roots<'_> {
+    fn trace(&self, heap: &mut Heap) {
+        for &value in self.stack {
+            heap.mark_value(value);
+        }
+        for frame in self.frames {
+            heap.mark_object(frame.closure);
+        }
+        for &upvalue in self.open_upvalues {
+            heap.mark_object(upvalue);
+        }
+        self.globals.trace(heap);
+        heap.mark_object(self.init_string);
+    }
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
     stack: OperandStack,
+    /// Keyed by interned name, so lookups hash a pointer rather than a string
+    globals: HashMap<ObjRef, Value>,
+    /// Upvalues that still point into the stack, ordered by slot
+    open_upvalues: Vec<ObjRef>,
+    heap: Heap,
+    /// Interned once, since every instantiation looks up the initializer
+    init_string: ObjRef,
+    trace_execution: bool,
 }
 
-impl<'v> VM<'v> {
-    pub fn new(chunk: &'v Chunk) -> VM {
-        VM {
-            chunk,
-            ip: 0,
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> VM {
+        let mut heap = Heap::new();
+        let init_string = heap.copy_string("init");
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: OperandStack::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap,
+            init_string,
+            trace_execution: false,
+        };
+        vm.define_native("clock", 0, native::clock);
+        vm
+    }
+
+    /// Exposes a Rust function to scripts as the global `name`
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let name = self.copy_string(name);
+        let native = self.alloc(Obj::Native(ObjNative::new(name, arity, function)));
+        self.globals.insert(name, Value::from(native));
+    }
+
+    pub fn interpret(&mut self, source: &str, options: Options) -> Result<(), InterpretError> {
+        self.reset_stack();
+        self.trace_execution = options.trace_execution;
+        let (heap, roots) = self.heap_and_roots();
+        let function = compile(source, heap, &roots, options)?;
+
+        let closure = self.alloc(Obj::Closure(ObjClosure::new(function, Vec::new())));
+        self.stack.push(Value::from(closure));
+        self.call(closure, 0)?;
+        self.run()
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack.truncate(0);
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let obj = self.heap.alloc(obj);
+        self.collect_garbage_if_needed(obj);
+        obj
+    }
+
+    fn copy_string(&mut self, chars: &str) -> ObjRef {
+        let s = self.heap.copy_string(chars);
+        self.collect_garbage_if_needed(s);
+        s
+    }
+
+    fn take_string(&mut self, chars: String) -> ObjRef {
+        let s = self.heap.take_string(chars);
+        self.collect_garbage_if_needed(s);
+        s
+    }
+
+    fn collect_garbage_if_needed(&mut self, new: ObjRef) {
+        let (heap, roots) = self.heap_and_roots();
+        heap.collect_if_needed(new, &roots);
+    }
+
+    /// Frees every object that running code can no longer reach
+    pub fn collect_garbage(&mut self) {
+        let (heap, roots) = self.heap_and_roots();
+        roots.trace(heap);
+        heap.collect();
+    }
+
+    /// Borrows the heap alongside everything that must survive a collection
+    fn heap_and_roots(&mut self) -> (&mut Heap, Roots<'_>) {
+        let roots = Roots {
+            stack: self.stack.top(self.stack.len()),
+            frames: &self.frames,
+            open_upvalues: &self.open_upvalues,
+            globals: &self.globals,
+            init_string: self.init_string,
+        };
+        (&mut self.heap, roots)
+    }
+
+    /// Changes the fields or methods in `table`, counting any growth
+    /// towards the next collection
+    fn update_table<F>(&mut self, table: &RefCell<HashMap<ObjRef, Value>>, update: F)
+    where
+        F: FnOnce(&mut HashMap<ObjRef, Value>),
+    {
+        let grown = {
+            let mut table = table.borrow_mut();
+            let before = table_size(&table);
+            update(&mut table);
+            table_size(&table) - before
+        };
+        if grown > 0 {
+            self.heap.grew(grown);
+            // whatever owns the table is still reachable from the stack
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+        }
+    }
+
+    /// Pushes a value that grows the stack. Instructions that pop
+    /// their operands first can't overflow, so they use `stack.push`.
+    fn push(&mut self, value: Value) -> Result<(), InterpretError> {
+        if self.stack.len() == STACK_MAX {
+            return Err(self.runtime_error("Stack overflow."));
         }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames
+            .last()
+            .expect("there is always a frame while running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("there is always a frame while running")
     }
 
     fn read_byte(&mut self) -> u8 {
-        let instruction = self.chunk.code[self.ip];
-        self.ip += 1;
+        let frame = self.frame_mut();
+        let instruction = frame.chunk().code[frame.ip];
+        frame.ip += 1;
         instruction
     }
 
-    fn binary_op<F>(&mut self, perform: F) -> Result<(), InterpretError>
+    fn binary_op<F, T>(&mut self, perform: F) -> Result<(), InterpretError>
     where
-        F: Fn(f64, f64) -> f64,
+        F: Fn(f64, f64) -> T,
+        T: Into<Value>,
     {
         assert!(
             self.stack.len() >= 2,
@@ -80,18 +341,302 @@ impl<'v> VM<'v> {
 
         let b = self.stack.pop();
         let a = self.stack.pop();
-        match (a, b) {
-            (Value::Number(n1), Value::Number(n2)) => {
-                self.stack.push(Value::Number(perform(n1, n2)));
+        match (a.as_number(), b.as_number()) {
+            (Some(n1), Some(n2)) => {
+                self.stack.push(perform(n1, n2).into());
                 Ok(())
             }
-            _ => Err(InterpretError::RuntimeError),
+            _ => Err(self.runtime_error("Operands must be numbers.")),
         }
     }
 
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte() as u16;
+        let low = self.read_byte() as u16;
+        high << 8 | low
+    }
+
     fn read_constant(&mut self) -> Value {
         let offset = self.read_byte() as usize;
-        self.chunk.constants[offset]
+        self.frame().chunk().constants[offset]
+    }
+
+    fn read_string(&mut self) -> ObjRef {
+        self.read_constant()
+            .as_obj()
+            .expect("expected a string constant")
+    }
+
+    /// Concatenates the two strings on top of the stack,
+    /// returning false if either operand isn't a string
+    fn concatenate(&mut self) -> bool {
+        let (a, b) = match (self.stack.peek(1).as_obj(), self.stack.peek(0).as_obj()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return false,
+        };
+        let chars = match (a.as_string(), b.as_string()) {
+            (Some(a), Some(b)) => a.chars.clone() + &b.chars,
+            _ => return false,
+        };
+
+        // keep the operands on the stack while allocating, in case that collects
+        let result = self.take_string(chars);
+        self.stack.pop();
+        self.stack.pop();
+        self.stack.push(Value::from(result));
+        true
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        if let Some(o) = callee.as_obj() {
+            match &*o {
+                Obj::Closure(_) => return self.call(o, arg_count),
+                Obj::Native(native) => return self.call_native(native, arg_count),
+                Obj::Class(_) => return self.call_class(o, arg_count),
+                Obj::BoundMethod(bound) => {
+                    // the receiver takes the callee's slot, where the method expects `this`
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack.set(slot, bound.receiver);
+                    return self.call(bound.method, arg_count);
+                }
+                _ => (),
+            }
+        }
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    fn call_native(&mut self, native: &ObjNative, arg_count: usize) -> Result<(), InterpretError> {
+        if arg_count != native.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            )));
+        }
+
+        let result = match (native.function)(&mut self.heap, self.stack.top(arg_count)) {
+            Ok(result) => result,
+            Err(message) => return Err(self.runtime_error(&message)),
+        };
+        // discard the arguments and the native itself
+        let len = self.stack.len() - arg_count - 1;
+        self.stack.truncate(len);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// Calling a class creates a new instance of it and runs its initializer
+    fn call_class(&mut self, class: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let instance = self.alloc(Obj::Instance(ObjInstance::new(class)));
+        // the instance replaces the class in the callee's slot
+        let slot = self.stack.len() - arg_count - 1;
+        self.stack.set(slot, Value::from(instance));
+
+        let initializer = class
+            .as_class()
+            .expect("only classes are instantiated")
+            .methods
+            .borrow()
+            .get(&self.init_string)
+            .cloned();
+        match initializer.map(|method| method.as_obj().expect("methods are closures")) {
+            Some(initializer) => self.call(initializer, arg_count),
+            None if arg_count != 0 => {
+                Err(self.runtime_error(&format!("Expected 0 arguments but got {}.", arg_count)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Calls the method `name` on the receiver sitting below the arguments,
+    /// or a callable stored in a field of that name
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let receiver = match self.peek_instance(arg_count) {
+            Some(receiver) => receiver,
+            None => return Err(self.runtime_error("Only instances have methods.")),
+        };
+
+        let instance = receiver.as_instance().expect("checked above");
+        let field = instance.fields.borrow().get(&name).cloned();
+        match field {
+            Some(value) => {
+                // fields shadow methods; the callee slot gets the field's value
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack.set(slot, value);
+                self.call_value(value, arg_count)
+            }
+            None => self.invoke_from_class(instance.class, name, arg_count),
+        }
+    }
+
+    /// Calls `class`'s method `name` on the receiver sitting below the arguments
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        let method = class
+            .as_class()
+            .expect("methods are looked up on classes")
+            .methods
+            .borrow()
+            .get(&name)
+            .cloned();
+        match method.map(|method| method.as_obj().expect("methods are closures")) {
+            Some(method) => self.call(method, arg_count),
+            None => Err(self.runtime_error(&format!("Undefined property '{}'.", name))),
+        }
+    }
+
+    /// Replaces the instance on top of the stack with its method `name`, bound to it
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), InterpretError> {
+        let method = class
+            .as_class()
+            .expect("instances belong to classes")
+            .methods
+            .borrow()
+            .get(&name)
+            .cloned();
+        let method = match method.map(|method| method.as_obj().expect("methods are closures")) {
+            Some(method) => method,
+            None => {
+                return Err(self.runtime_error(&format!("Undefined property '{}'.", name)));
+            }
+        };
+
+        let receiver = self.stack.peek(0);
+        let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod::new(receiver, method)));
+        self.stack.pop();
+        self.stack.push(Value::from(bound));
+        Ok(())
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let arity = closure
+            .as_closure()
+            .expect("only closures can be called")
+            .function()
+            .arity;
+        if arg_count != arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            )));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    /// Returns the upvalue for the variable in `slot`,
+    /// reusing the open one if another closure already captured it
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self
+            .open_upvalues
+            .binary_search_by_key(&slot, |upvalue| open_upvalue_slot(*upvalue));
+
+        match position {
+            Ok(idx) => self.open_upvalues[idx],
+            Err(idx) => {
+                let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::new(slot)));
+                self.open_upvalues.insert(idx, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    /// Moves every variable at or above `last` off the stack and into its upvalue
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = open_upvalue_slot(upvalue);
+            if slot < last {
+                break;
+            }
+
+            let value = self.stack.get(slot);
+            upvalue
+                .as_upvalue()
+                .expect("open upvalues are upvalues")
+                .state
+                .set(UpvalueState::Closed(value));
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// The instance `distance` values down the stack, if it is one
+    fn peek_instance(&self, distance: usize) -> Option<ObjRef> {
+        self.stack
+            .peek(distance)
+            .as_obj()
+            .filter(|o| o.as_instance().is_some())
+    }
+
+    fn read_upvalue(&self, idx: usize) -> Value {
+        let upvalue = self.frame().closure().upvalues[idx];
+        match upvalue
+            .as_upvalue()
+            .expect("closures capture upvalues")
+            .state
+            .get()
+        {
+            UpvalueState::Open(slot) => self.stack.get(slot),
+            UpvalueState::Closed(value) => value,
+        }
+    }
+
+    fn write_upvalue(&mut self, idx: usize, value: Value) {
+        let upvalue = self.frame().closure().upvalues[idx];
+        let state = &upvalue
+            .as_upvalue()
+            .expect("closures capture upvalues")
+            .state;
+        match state.get() {
+            UpvalueState::Open(slot) => self.stack.set(slot, value),
+            UpvalueState::Closed(_) => state.set(UpvalueState::Closed(value)),
+        }
+    }
+
+    /// Captures the call stack for an error and resets the VM,
+    /// so it can go on to run more code
+    fn runtime_error(&mut self, message: &str) -> InterpretError {
+        let trace: Vec<TraceEntry> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                // ip has already moved past the failing instruction
+                let position = frame.chunk().position(frame.ip.saturating_sub(1));
+                TraceEntry {
+                    line: position.line,
+                    column: position.column,
+                    function: frame.function().name.map(|name| name.to_string()),
+                }
+            })
+            .collect();
+        let span = self.frames.last().map_or(0..0, |frame| {
+            frame
+                .chunk()
+                .position(frame.ip.saturating_sub(1))
+                .span
+                .clone()
+        });
+        self.reset_stack();
+
+        InterpretError::RuntimeError(RuntimeError {
+            message: message.to_owned(),
+            line: trace.first().map_or(0, |entry| entry.line),
+            column: trace.first().map_or(0, |entry| entry.column),
+            span,
+            trace,
+        })
     }
 
     fn print_debug_info(&self) {
@@ -100,27 +645,253 @@ impl<'v> VM<'v> {
 
     pub fn run(&mut self) -> Result<(), InterpretError> {
         loop {
-            let instruction = self.chunk.code[self.ip];
-            self.ip += 1;
+            let instruction = self.read_byte();
 
-            self.print_debug_info();
+            if self.trace_execution {
+                self.print_debug_info();
+            }
 
             match OpCode::from_int(instruction) {
                 OpCode::Constant => {
                     let c = self.read_constant();
-                    self.stack.push(c);
+                    self.push(c)?;
                 }
                 OpCode::Return => {
+                    let result = self.stack.pop();
+                    let frame = self.frames.pop().expect("returning from a frame");
+                    self.close_upvalues(frame.slots);
+                    if self.frames.is_empty() {
+                        // pop the script function itself
+                        self.stack.pop();
+                        return Ok(());
+                    }
+
+                    self.stack.truncate(frame.slots);
+                    self.stack.push(result);
+                }
+                OpCode::Closure => {
+                    let function = self
+                        .read_constant()
+                        .as_obj()
+                        .expect("expected a function constant");
+                    let upvalue_count = function
+                        .as_function()
+                        .expect("closures wrap functions")
+                        .upvalue_count;
+
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure().upvalues[index]);
+                        }
+                    }
+
+                    let closure = self.alloc(Obj::Closure(ObjClosure::new(function, upvalues)));
+                    self.push(Value::from(closure))?;
+                }
+                OpCode::GetUpvalue => {
+                    let idx = self.read_byte() as usize;
+                    let value = self.read_upvalue(idx);
+                    self.push(value)?;
+                }
+                OpCode::SetUpvalue => {
+                    let idx = self.read_byte() as usize;
+                    let value = self.stack.peek(0);
+                    self.write_upvalue(idx, value);
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.stack.peek(arg_count);
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    let class = self.alloc(Obj::Class(ObjClass::new(name)));
+                    self.push(Value::from(class))?;
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    let instance = match self.peek_instance(0) {
+                        Some(instance) => instance,
+                        None => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+
+                    let instance = instance.as_instance().expect("checked above");
+                    let field = instance.fields.borrow().get(&name).cloned();
+                    match field {
+                        Some(value) => {
+                            self.stack.pop();
+                            self.stack.push(value);
+                        }
+                        // fields shadow methods
+                        None => self.bind_method(instance.class, name)?,
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    let instance = match self.peek_instance(1) {
+                        Some(instance) => instance,
+                        None => return Err(self.runtime_error("Only instances have fields.")),
+                    };
+
+                    let value = self.stack.pop();
+                    let fields = &instance.as_instance().expect("checked above").fields;
+                    self.update_table(fields, |fields| {
+                        fields.insert(name, value);
+                    });
+                    // an assignment evaluates to the assigned value
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                OpCode::Method => {
+                    let name = self.read_string();
+                    let method = self.stack.peek(0);
+                    let class = self
+                        .stack
+                        .peek(1)
+                        .as_obj()
+                        .expect("methods are defined on classes");
+                    let methods = &class
+                        .as_class()
+                        .expect("methods are defined on classes")
+                        .methods;
+                    self.update_table(methods, |methods| {
+                        methods.insert(name, method);
+                    });
+                    self.stack.pop();
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(name, arg_count)?;
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.stack.peek(1).as_obj() {
+                        Some(o) if o.as_class().is_some() => o,
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    let subclass = self.stack.peek(0).as_obj().expect("only classes inherit");
+
+                    // copy the methods down now; the subclass's own ones are
+                    // defined afterwards and override them
+                    let inherited = superclass
+                        .as_class()
+                        .expect("checked above")
+                        .methods
+                        .borrow()
+                        .clone();
+                    let methods = &subclass.as_class().expect("only classes inherit").methods;
+                    self.update_table(methods, |methods| methods.extend(inherited));
+                    self.stack.pop();
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let superclass = self.stack.pop().as_obj().expect("super is always a class");
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = self.stack.pop().as_obj().expect("super is always a class");
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+                OpCode::Print => {
                     print_value(self.stack.pop());
-                    return Ok(());
+                    println!();
+                }
+                OpCode::Pop => {
+                    self.stack.pop();
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    let value = self.stack.pop();
+                    self.globals.insert(name, value);
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    match self.globals.get(&name) {
+                        Some(&value) => self.push(value)?,
+                        None => {
+                            return Err(
+                                self.runtime_error(&format!("Undefined variable '{}'.", name))
+                            );
+                        }
+                    }
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    if !self.globals.contains_key(&name) {
+                        return Err(self.runtime_error(&format!("Undefined variable '{}'.", name)));
+                    }
+                    // assignment is an expression, so the value stays on the stack
+                    self.globals.insert(name, self.stack.peek(0));
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    let value = self.stack.get(slot);
+                    self.push(value)?;
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    let value = self.stack.peek(0);
+                    self.stack.set(slot, value);
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short() as usize;
+                    if is_falsey(self.stack.peek(0)) {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Not => {
+                    let value = self.stack.pop();
+                    self.stack.push(Value::from(is_falsey(value)));
+                }
+                OpCode::Equal => {
+                    let b = self.stack.pop();
+                    let a = self.stack.pop();
+                    self.stack.push(Value::from(a == b));
+                }
+                OpCode::Greater => {
+                    self.binary_op(|a, b| a > b)?;
                 }
-                OpCode::Negate => match self.stack.pop() {
-                    Value::Number(n) => self.stack.push(Value::Number(-n)),
-                    _ => return Err(InterpretError::RuntimeError),
+                OpCode::Less => {
+                    self.binary_op(|a, b| a < b)?;
+                }
+                OpCode::Nil => self.push(Value::nil())?,
+                OpCode::True => self.push(Value::from(true))?,
+                OpCode::False => self.push(Value::from(false))?,
+                OpCode::Negate => match self.stack.pop().as_number() {
+                    Some(n) => self.stack.push(Value::from(-n)),
+                    None => return Err(self.runtime_error("Operand must be a number.")),
                 },
 
                 OpCode::Add => {
-                    self.binary_op(|a, b| a + b)?;
+                    if !self.concatenate() {
+                        let a = self.stack.peek(1).as_number();
+                        let b = self.stack.peek(0).as_number();
+                        if a.is_none() || b.is_none() {
+                            let message = "Operands must be two numbers or two strings.";
+                            return Err(self.runtime_error(message));
+                        }
+                        self.binary_op(|a, b| a + b)?;
+                    }
                 }
                 OpCode::Subtract => {
                     self.binary_op(|a, b| a - b)?;
@@ -131,8 +902,580 @@ impl<'v> VM<'v> {
                 OpCode::Divide => {
                     self.binary_op(|a, b| a / b)?;
                 }
-                OpCode::Unknown => return Err(InterpretError::RuntimeError),
+                OpCode::Unknown => return Err(self.runtime_error("Unknown opcode.")),
             }
         }
     }
 }
+
+fn open_upvalue_slot(upvalue: ObjRef) -> usize {
+    match upvalue.as_upvalue().map(|u| u.state.get()) {
+        Some(UpvalueState::Open(slot)) => slot,
+        _ => panic!("expected an open upvalue"),
+    }
+}
+
+/// `nil` and `false` are falsey, every other value is truthy
+fn is_falsey(value: Value) -> bool {
+    value.is_nil() || value.as_bool() == Some(false)
+}
+
+#[cfg(test)]
+impl VM {
+    fn global(&mut self, name: &str) -> Value {
+        let name = self.heap.copy_string(name);
+        self.globals[&name]
+    }
+
+    /// The message of the runtime error running `source` fails with
+    fn error_message(&mut self, source: &str) -> String {
+        match self.interpret(source, Options::default()) {
+            Err(InterpretError::RuntimeError(error)) => error.message,
+            _ => panic!("expected a runtime error"),
+        }
+    }
+}
+
+#[test]
+fn vm_define_and_assign_globals() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "var a = 1; var b; a = a + 2; b = a * 2;",
+        Options::default(),
+    )
+    .unwrap();
+    assert_eq!(vm.global("a").as_number(), Some(3.0));
+    assert_eq!(vm.global("b").as_number(), Some(6.0));
+}
+
+#[test]
+fn vm_block_scoped_locals() {
+    let source = "var r; { var a = 2; { var a = 3; r = a; } a = a + 1; r = r + a; }";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert_eq!(vm.global("r").as_number(), Some(6.0));
+    assert_eq!(vm.stack.len(), 0);
+}
+
+#[test]
+fn vm_if_else() {
+    let source = "var n; var r; if (n) r = 1; else r = 2; if (r) r = r + 10;";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert_eq!(vm.global("r").as_number(), Some(12.0));
+}
+
+#[test]
+fn vm_while_and_for_loops() {
+    let source = "var n; var r = 0;
+        var go = 1; while (go) { r = r + 1; go = n; }
+        for (var i = 3; i; i = n) { r = r + i; }";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert_eq!(vm.global("r").as_number(), Some(4.0));
+    assert_eq!(vm.stack.len(), 0);
+}
+
+#[test]
+fn vm_comparison_and_equality() {
+    let source = "var n;
+        var a = 1 < 2; var b = 2 <= 1; var c = 3 >= 3; var d = !(1 > 2);
+        var e = n == n; var f = 1 != 1; var g = n == 0; var h = !n;";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    let expected = [
+        ("a", true),
+        ("b", false),
+        ("c", true),
+        ("d", true),
+        ("e", true),
+        ("f", false),
+        ("g", false),
+        ("h", true),
+    ];
+    for (name, value) in expected.iter() {
+        assert!(vm.global(name) == Value::from(*value), "{}", name);
+    }
+}
+
+#[test]
+fn vm_literals() {
+    let source = "var a = true; var b = !false; var c = nil; var d = nil == false;";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("a") == Value::from(true));
+    assert!(vm.global("b") == Value::from(true));
+    assert!(vm.global("c") == Value::nil());
+    assert!(vm.global("d") == Value::from(false));
+}
+
+#[test]
+fn vm_logical_operators_short_circuit() {
+    // reading `missing` would be a runtime error, so it must never be evaluated
+    let source = "var a = false and missing; var b = 1 or missing;
+        var c = nil or 2; var d = true and 3; var e = false or nil and missing;";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("a") == Value::from(false));
+    assert!(vm.global("b") == Value::from(1.0));
+    assert!(vm.global("c") == Value::from(2.0));
+    assert!(vm.global("d") == Value::from(3.0));
+    assert!(vm.global("e") == Value::nil());
+    assert_eq!(vm.stack.len(), 0);
+}
+
+#[test]
+fn vm_comparison_requires_numbers() {
+    assert_eq!(
+        VM::new().error_message("var n; print n < 1;"),
+        "Operands must be numbers."
+    );
+}
+
+#[test]
+fn vm_string_concatenation() {
+    let source = "var a = \"lox\"; var b = a + \"-\" + \"vm\"; var c = b == \"lox-vm\";";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("c") == Value::from(true));
+    // concatenation produces the same interned string as the literal
+    let expected = vm.heap.copy_string("lox-vm");
+    assert!(vm.global("b") == Value::from(expected));
+
+    assert_eq!(
+        VM::new().error_message("print \"a\" + 1;"),
+        "Operands must be two numbers or two strings."
+    );
+}
+
+#[test]
+fn vm_function_calls() {
+    let source = "
+        fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        fun noop() {}
+        var a = fib(10);
+        var b = noop();";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("a") == Value::from(55.0));
+    assert!(vm.global("b") == Value::nil());
+    assert_eq!(vm.stack.len(), 0);
+}
+
+#[test]
+fn vm_closures_capture_variables() {
+    let source = "
+        fun counter() {
+            var count = 0;
+            fun increment() { count = count + 1; return count; }
+            return increment;
+        }
+        var c1 = counter();
+        var c2 = counter();
+        c1(); c1();
+        var a = c1();
+        var b = c2();
+
+        var getter; var setter;
+        {
+            var shared = \"before\";
+            fun get() { return shared; }
+            fun set(v) { shared = v; }
+            getter = get; setter = set;
+        }
+        setter(\"after\");
+        var s = getter();";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("a") == Value::from(3.0));
+    assert!(vm.global("b") == Value::from(1.0));
+    let after = vm.heap.copy_string("after");
+    assert!(vm.global("s") == Value::from(after));
+    assert!(vm.open_upvalues.is_empty());
+    assert_eq!(vm.stack.len(), 0);
+}
+
+#[test]
+fn vm_native_functions() {
+    fn sum(_heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+        match (args[0].as_number(), args[1].as_number()) {
+            (Some(a), Some(b)) => Ok(Value::from(a + b)),
+            _ => Err("sum() takes two numbers.".to_owned()),
+        }
+    }
+
+    let mut vm = VM::new();
+    vm.define_native("sum", 2, sum);
+    vm.interpret(
+        "var a = sum(1, 2) * 2; var t = clock(); var elapsed = clock() - t;",
+        Options::default(),
+    )
+    .unwrap();
+    assert!(vm.global("a") == Value::from(6.0));
+    assert!(vm.global("elapsed").as_number().unwrap() >= 0.0);
+    assert_eq!(vm.stack.len(), 0);
+
+    assert_eq!(
+        vm.error_message("sum(1);"),
+        "Expected 2 arguments but got 1."
+    );
+    assert_eq!(vm.error_message("sum(1, nil);"), "sum() takes two numbers.");
+}
+
+#[test]
+fn vm_native_functions_return_objects() {
+    fn greet(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+        let name = args[0]
+            .as_obj()
+            .and_then(|o| o.as_string().map(|s| s.chars.clone()));
+        match name {
+            Some(name) => Ok(Value::from(heap.take_string(format!("hello {}", name)))),
+            None => Err("greet() takes a string.".to_owned()),
+        }
+    }
+
+    let mut vm = VM::new();
+    vm.heap.stress_gc = true;
+    vm.define_native("greet", 1, greet);
+    vm.interpret("var g = greet(\"lox\") + \"!\";", Options::default())
+        .unwrap();
+    let expected = vm.heap.copy_string("hello lox!");
+    assert!(vm.global("g") == Value::from(expected));
+}
+
+#[test]
+fn vm_collects_garbage() {
+    let source = "
+        fun make(suffix) {
+            var s = \"x\" + suffix;
+            fun get() { return s + \"!\"; }
+            return get;
+        }
+        var kept = make(\"y\");
+        var garbage = \"\";
+        for (var i = 0; i < 50; i = i + 1) {
+            garbage = garbage + \"z\";
+            make(garbage);
+        }
+        var result = kept();";
+    let mut vm = VM::new();
+    vm.heap.stress_gc = true;
+    vm.interpret(source, Options::default()).unwrap();
+    let expected = vm.heap.copy_string("xy!");
+    assert!(vm.global("result") == Value::from(expected));
+
+    let before = vm.heap.bytes_allocated();
+    vm.interpret("garbage = nil;", Options::default()).unwrap();
+    vm.collect_garbage();
+    assert!(vm.heap.bytes_allocated() < before);
+}
+
+#[test]
+fn vm_counts_fields_towards_collection() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "class C { init() { this.n = 0; } } var c = C();",
+        Options::default(),
+    )
+    .unwrap();
+    let fields: String = (0..100).map(|i| format!("c.f{} = {};", i, i)).collect();
+    vm.interpret(&fields, Options::default()).unwrap();
+    let table = table_size(
+        &vm.global("c")
+            .as_obj()
+            .unwrap()
+            .as_instance()
+            .unwrap()
+            .fields
+            .borrow(),
+    );
+    vm.collect_garbage();
+    let with_fields = vm.heap.bytes_allocated();
+
+    vm.interpret("c = nil;", Options::default()).unwrap();
+    vm.collect_garbage();
+    assert!(with_fields - vm.heap.bytes_allocated() >= table);
+
+    // "init" is a root, so initializers are still found after collecting
+    vm.heap.stress_gc = true;
+    vm.interpret("c = C();", Options::default()).unwrap();
+    let n = vm.heap.copy_string("n");
+    let instance = vm.global("c").as_obj().unwrap();
+    assert!(instance.as_instance().unwrap().fields.borrow()[&n] == Value::from(0.0));
+}
+
+#[test]
+fn vm_keeps_roots_alive_while_compiling() {
+    let mut vm = VM::new();
+    vm.heap.stress_gc = true;
+    vm.interpret("var x = \"pad\" + \"ding\";", Options::default())
+        .unwrap();
+    // collections while compiling must not free "init", or initializers stop running
+    let source = "class A { init() { this.v = 7; } } var a = A(); var r = a.v;";
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("r") == Value::from(7.0));
+}
+
+#[test]
+fn vm_classes_and_fields() {
+    let source = "
+        class Point {}
+        var p = Point();
+        p.x = 1;
+        p.y = p.x + 2;
+        var sum = p.x + p.y;
+        var chained = p.z = 4;";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("sum") == Value::from(4.0));
+    assert!(vm.global("chained") == Value::from(4.0));
+    assert_eq!(
+        vm.global("p").as_obj().unwrap().to_string(),
+        "Point instance"
+    );
+    assert_eq!(vm.stack.len(), 0);
+
+    let errors = [
+        (
+            "class A {} var a = A(); print a.missing;",
+            "Undefined property 'missing'.",
+        ),
+        (
+            "var n = 1; print n.field;",
+            "Only instances have properties.",
+        ),
+        ("var n = 1; n.field = 2;", "Only instances have fields."),
+        ("class A {} A(1);", "Expected 0 arguments but got 1."),
+    ];
+    for (source, message) in errors.iter() {
+        assert_eq!(VM::new().error_message(source), *message);
+    }
+}
+
+#[test]
+fn vm_methods_and_initializers() {
+    let source = "
+        class Counter {
+            init(start) { this.count = start; }
+            increment() { this.count = this.count + 1; return this; }
+            get() { return this.count; }
+        }
+        var c = Counter(10);
+        var a = c.increment().increment().get();
+        var bound = c.get;
+        c.increment();
+        var b = bound();
+        var reinit = c.init(1) == c;
+
+        class Box {
+            init() { this.items = 0; return; }
+            adder() { fun add() { this.items = this.items + 1; } return add; }
+        }
+        var box = Box();
+        var add = box.adder();
+        add(); add();
+        var items = box.items;";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("a") == Value::from(12.0));
+    assert!(vm.global("b") == Value::from(13.0));
+    assert!(vm.global("reinit") == Value::from(true));
+    assert!(vm.global("items") == Value::from(2.0));
+    assert_eq!(vm.global("bound").as_obj().unwrap().to_string(), "<fn get>");
+    assert_eq!(vm.stack.len(), 0);
+
+    let errors = [
+        (
+            "class A { init(a) {} } A();",
+            "Expected 1 arguments but got 0.",
+        ),
+        (
+            "class A { init() {} } A(1);",
+            "Expected 0 arguments but got 1.",
+        ),
+        ("class A {} A().missing();", "Undefined property 'missing'."),
+    ];
+    for (source, message) in errors.iter() {
+        assert_eq!(VM::new().error_message(source), *message);
+    }
+}
+
+#[test]
+fn vm_inheritance_and_super() {
+    let source = "
+        class A {
+            init(n) { this.n = n; }
+            name() { return \"A\"; }
+            describe() { return this.name() + \"!\"; }
+        }
+        class B < A {
+            init(n) { super.init(n * 2); }
+            name() { return \"B\" + super.name(); }
+            parent() { var method = super.name; return method; }
+        }
+        var b = B(2);
+        var n = b.n;
+        var described = b.describe();
+        var parent = b.parent()();";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("n") == Value::from(4.0));
+    let described = vm.heap.copy_string("BA!");
+    assert!(vm.global("described") == Value::from(described));
+    let parent = vm.heap.copy_string("A");
+    assert!(vm.global("parent") == Value::from(parent));
+    assert_eq!(vm.stack.len(), 0);
+
+    let errors = [
+        ("var A = 1; class B < A {}", "Superclass must be a class."),
+        (
+            "class A {} class B < A { f() { return super.missing; } } B().f();",
+            "Undefined property 'missing'.",
+        ),
+    ];
+    for (source, message) in errors.iter() {
+        assert_eq!(VM::new().error_message(source), *message);
+    }
+}
+
+#[test]
+fn vm_invoke_methods_and_fields() {
+    let source = "
+        class A {
+            init() { this.calls = 0; }
+            add(a, b) { this.calls = this.calls + 1; return a + b; }
+        }
+        fun double(n) { return n * 2; }
+        var a = A();
+        var sum = a.add(1, 2) + a.add(3, 4);
+        a.add = double;
+        var doubled = a.add(5);
+        var calls = a.calls;";
+    let mut vm = VM::new();
+    vm.interpret(source, Options::default()).unwrap();
+    assert!(vm.global("sum") == Value::from(10.0));
+    assert!(vm.global("doubled") == Value::from(10.0));
+    assert!(vm.global("calls") == Value::from(2.0));
+    assert_eq!(vm.stack.len(), 0);
+
+    let errors = [
+        ("var n = 1; n.f();", "Only instances have methods."),
+        ("class A {} A().missing();", "Undefined property 'missing'."),
+        (
+            "class A {} var a = A(); a.f = 1; a.f();",
+            "Can only call functions and classes.",
+        ),
+        (
+            "class A { f(x) {} } A().f();",
+            "Expected 1 arguments but got 0.",
+        ),
+    ];
+    for (source, message) in errors.iter() {
+        assert_eq!(VM::new().error_message(source), *message);
+    }
+}
+
+#[test]
+fn vm_runtime_errors_carry_trace() {
+    let source = "fun outer() {
+  inner();
+}
+fun inner() {
+  return -\"a\";
+}
+outer();";
+    let mut vm = VM::new();
+    let error = match vm.interpret(source, Options::default()) {
+        Err(InterpretError::RuntimeError(error)) => error,
+        _ => panic!("expected a runtime error"),
+    };
+    assert_eq!(error.message, "Operand must be a number.");
+    assert_eq!(
+        (error.line, error.column, error.span.clone()),
+        (5, 10, 50..51)
+    );
+    assert_eq!(
+        error.to_string(),
+        "Operand must be a number.
+[line 5] in inner()
+[line 2] in outer()
+[line 7] in script"
+    );
+
+    // the stack is reset, so the VM can keep running
+    assert_eq!(vm.stack.len(), 0);
+    assert!(vm.frames.is_empty());
+    vm.interpret("var a = 1 + 2;", Options::default()).unwrap();
+    assert!(vm.global("a") == Value::from(3.0));
+
+    let messages = [
+        ("1 < nil;", "Operands must be numbers."),
+        ("1 + nil;", "Operands must be two numbers or two strings."),
+        ("\"a\" + 1;", "Operands must be two numbers or two strings."),
+    ];
+    for (source, message) in messages.iter() {
+        match vm.interpret(source, Options::default()) {
+            Err(InterpretError::RuntimeError(error)) => assert_eq!(error.message, *message),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+}
+
+#[test]
+fn vm_call_errors() {
+    let arity = "fun f(a) {} f(1, 2);";
+    assert_eq!(
+        VM::new().error_message(arity),
+        "Expected 1 arguments but got 2."
+    );
+
+    let not_callable = "var a = 1; a();";
+    assert_eq!(
+        VM::new().error_message(not_callable),
+        "Can only call functions and classes."
+    );
+
+    let overflow = "fun f() { f(); } f();";
+    assert_eq!(VM::new().error_message(overflow), "Stack overflow.");
+}
+
+#[test]
+fn vm_operand_stack_overflow() {
+    // each frame holds more than 256 values, so the stack fills up
+    // before the frames run out
+    let locals: String = (0..250).map(|i| format!("var a{};", i)).collect();
+    let sum = "1 + (".repeat(20) + "f(n - 1)" + &")".repeat(20);
+    let source = format!(
+        "fun f(n) {{ {} if (n == 0) return 0; return {}; }} print f(100);",
+        locals, sum
+    );
+    let error = match VM::new().interpret(&source, Options::default()) {
+        Err(InterpretError::RuntimeError(error)) => error,
+        _ => panic!("expected a runtime error"),
+    };
+    assert_eq!(error.message, "Stack overflow.");
+    assert!(error.trace.len() < FRAMES_MAX);
+}
+
+#[test]
+fn vm_fits_on_a_small_thread_stack() {
+    // the operand stack is far bigger than this, so it mustn't be built inline
+    let handle = std::thread::Builder::new()
+        .stack_size(64 * 1024)
+        .spawn(|| {
+            let mut vm = VM::new();
+            vm.interpret("var a = 1 + 2;", Options::default()).is_ok()
+        })
+        .unwrap();
+    assert!(handle.join().unwrap());
+}
+
+#[test]
+fn vm_undefined_global() {
+    assert_eq!(
+        VM::new().error_message("print a;"),
+        "Undefined variable 'a'."
+    );
+
+    assert_eq!(VM::new().error_message("a = 1;"), "Undefined variable 'a'.");
+}